@@ -83,6 +83,54 @@ CREATE TABLE payment_tokens
 )
   COMMENT = '決済トークンテーブル';
 
+DROP TABLE IF EXISTS payments;
+CREATE TABLE payments
+(
+  id              VARCHAR(26)                             NOT NULL COMMENT '支払いID',
+  ride_id         VARCHAR(26)                             NOT NULL COMMENT 'ライドID',
+  user_id         VARCHAR(26)                             NOT NULL COMMENT 'ユーザーID',
+  amount          INTEGER                                 NOT NULL COMMENT '請求額',
+  idempotency_key VARCHAR(26)                             NOT NULL COMMENT '決済サービスに送る Idempotency-Key',
+  status          ENUM ('PENDING', 'PROCESSING', 'SUCCEEDED', 'FAILED') NOT NULL DEFAULT 'PENDING' COMMENT '決済状態',
+  payment_token_id VARCHAR(26)                            NULL COMMENT '請求に成功した支払い方法ID',
+  charged_idempotency_key VARCHAR(64)                     NULL COMMENT '請求に成功したときに送った Idempotency-Key',
+  attempts        INTEGER                                 NOT NULL DEFAULT 0 COMMENT '決済サービスへの請求回数',
+  last_error      TEXT                                    NULL COMMENT '最後に失敗したときのエラー',
+  next_attempt_at DATETIME(6)                             NOT NULL DEFAULT CURRENT_TIMESTAMP(6) COMMENT '次に請求してよい日時',
+  claimed_by      VARCHAR(26)                             NULL COMMENT '処理中のワーカー',
+  locked_until    DATETIME(6)                             NULL COMMENT 'この日時を過ぎても処理中なら他のワーカーが引き取る',
+  created_at      DATETIME(6)                             NOT NULL DEFAULT CURRENT_TIMESTAMP(6) COMMENT '作成日時',
+  updated_at      DATETIME(6)                             NOT NULL DEFAULT CURRENT_TIMESTAMP(6) ON UPDATE CURRENT_TIMESTAMP(6) COMMENT '更新日時',
+  PRIMARY KEY (id),
+  UNIQUE (ride_id)
+)
+  COMMENT = '決済サービスへの請求 (outbox) テーブル';
+
+create index payments_status_next_attempt_at on payments (status, next_attempt_at);
+
+DROP TABLE IF EXISTS refunds;
+CREATE TABLE refunds
+(
+  id              VARCHAR(26)                                           NOT NULL COMMENT '返金ID',
+  ride_id         VARCHAR(26)                                           NOT NULL COMMENT 'ライドID',
+  payment_id      VARCHAR(26)                                           NOT NULL COMMENT '返金元の支払いID',
+  amount          INTEGER                                               NOT NULL COMMENT '返金額',
+  reason          TEXT                                                  NOT NULL COMMENT '返金理由',
+  requested_by    ENUM ('OWNER', 'ADMIN')                               NOT NULL COMMENT '返金を求めた人',
+  idempotency_key VARCHAR(26)                                           NOT NULL COMMENT '決済サービスに送る Idempotency-Key',
+  status          ENUM ('PENDING', 'SUCCEEDED', 'FAILED', 'UNRESOLVED') NOT NULL DEFAULT 'PENDING' COMMENT '返金状態',
+  attempts        INTEGER                                               NOT NULL DEFAULT 0 COMMENT '決済サービスに依頼した回数',
+  last_error      TEXT                                                  NULL COMMENT '失敗したときのエラー',
+  next_attempt_at DATETIME(6)                                           NOT NULL DEFAULT CURRENT_TIMESTAMP(6) COMMENT '次に送り直す日時',
+  created_at      DATETIME(6)                                           NOT NULL DEFAULT CURRENT_TIMESTAMP(6) COMMENT '作成日時',
+  updated_at      DATETIME(6)                                           NOT NULL DEFAULT CURRENT_TIMESTAMP(6) ON UPDATE CURRENT_TIMESTAMP(6) COMMENT '更新日時',
+  PRIMARY KEY (id)
+)
+  COMMENT = '返金テーブル';
+
+create index refunds_ride_id on refunds (ride_id);
+create index refunds_status_next_attempt_at on refunds (status, next_attempt_at);
+
 DROP TABLE IF EXISTS rides;
 CREATE TABLE rides
 (
@@ -108,7 +156,7 @@ CREATE TABLE ride_statuses
 (
   id              VARCHAR(26)                                                                NOT NULL,
   ride_id VARCHAR(26)                                                                        NOT NULL COMMENT 'ライドID',
-  status          ENUM ('MATCHING', 'ENROUTE', 'PICKUP', 'CARRYING', 'ARRIVED', 'COMPLETED') NOT NULL COMMENT '状態',
+  status          ENUM ('MATCHING', 'ENROUTE', 'PICKUP', 'CARRYING', 'ARRIVED', 'COMPLETED', 'CANCELED') NOT NULL COMMENT '状態',
   created_at      DATETIME(6)                                                                NOT NULL DEFAULT CURRENT_TIMESTAMP(6) COMMENT '状態変更日時',
   app_sent_at     DATETIME(6)                                                                NULL COMMENT 'ユーザーへの状態通知日時',
   chair_sent_at   DATETIME(6)                                                                NULL COMMENT '椅子への状態通知日時',
@@ -118,6 +166,20 @@ CREATE TABLE ride_statuses
 
 create index ride_statuses_ride_id_created_at on ride_statuses (ride_id, created_at);
 
+DROP TABLE IF EXISTS ride_matching_events;
+CREATE TABLE ride_matching_events
+(
+  id         VARCHAR(26)                    NOT NULL,
+  ride_id    VARCHAR(26)                    NOT NULL COMMENT 'ライドID',
+  chair_id   VARCHAR(26)                    NOT NULL COMMENT '割り当てを外された椅子ID',
+  event      ENUM ('REJECTED', 'TIMED_OUT') NOT NULL COMMENT '割り当てを外された理由',
+  created_at DATETIME(6)                    NOT NULL DEFAULT CURRENT_TIMESTAMP(6) COMMENT '発生日時',
+  PRIMARY KEY (id)
+)
+  COMMENT = '椅子へのライド割り当ての取り消し履歴テーブル';
+
+create index ride_matching_events_ride_id on ride_matching_events (ride_id);
+
 DROP TABLE IF EXISTS owners;
 CREATE TABLE owners
 (
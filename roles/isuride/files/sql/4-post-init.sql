@@ -14,3 +14,48 @@ JOIN (
 ) distance_table
 ON cl.chair_id = distance_table.chair_id
 SET cl.total_distance = distance_table.total_distance;
+
+-- 1 人のユーザーが複数の支払い方法を持てるようにする。
+-- 既存の決済トークンはユーザーに 1 つずつなので、ユーザーID をそのまま支払い方法ID にしてデフォルトにする
+ALTER TABLE payment_tokens
+  ADD COLUMN id         VARCHAR(26) NULL COMMENT '支払い方法ID' FIRST,
+  ADD COLUMN is_default TINYINT(1)  NOT NULL DEFAULT 0 COMMENT 'デフォルトの支払い方法かどうか' AFTER token;
+UPDATE payment_tokens SET id = user_id, is_default = 1;
+ALTER TABLE payment_tokens
+  MODIFY COLUMN id VARCHAR(26) NOT NULL COMMENT '支払い方法ID',
+  DROP PRIMARY KEY,
+  ADD PRIMARY KEY (id),
+  ADD UNIQUE payment_tokens_user_id_token (user_id, token);
+
+-- ライドを作ったときのサージの倍率を固定しておく。既存のライドはサージ無し
+ALTER TABLE rides
+  ADD COLUMN surge_multiplier DOUBLE NOT NULL DEFAULT 1 COMMENT 'サージの倍率' AFTER destination_longitude;
+-- サージの倍率を決めるときに、椅子を待っているライドを乗車位置の範囲で数える
+create index rides_chair_id_pickup on rides (chair_id, pickup_latitude, pickup_longitude);
+
+-- ライドを作ったときに請求する運賃を固定する。既存のライドは以前の固定の運賃で計算する。
+-- オーナーの売上は椅子のモデルが分かる完了時に割引前の運賃で固定する。既存のライドは今の料金設定で計算する
+ALTER TABLE rides
+  ADD COLUMN quoted_fare INTEGER NULL              COMMENT 'ライドを作ったときに固定した運賃' AFTER surge_multiplier,
+  ADD COLUMN sale        INTEGER NULL              COMMENT '完了したときに固定したオーナーの売上' AFTER quoted_fare,
+  ADD COLUMN surge_sale  INTEGER NOT NULL DEFAULT 0 COMMENT 'オーナーの売上のうちサージの分' AFTER sale;
+
+-- 1 つの見積もりで作れるライドは 1 つだけにする
+ALTER TABLE rides
+  ADD COLUMN quote_id VARCHAR(26) NULL COMMENT '作るのに使った見積もりのID' AFTER surge_sale,
+  ADD UNIQUE rides_quote_id (quote_id);
+
+-- クーポンごとに割引の種類や使える条件を持たせる。
+-- 既存のクーポンは定額で期限なし。初回登録クーポンを最優先、次に招待クーポンを使う
+ALTER TABLE coupons
+  ADD COLUMN campaign       VARCHAR(32)                 NOT NULL DEFAULT '' COMMENT 'キャンペーン',
+  ADD COLUMN discount_type  ENUM ('FLAT', 'PERCENTAGE') NOT NULL DEFAULT 'FLAT' COMMENT '割引の種類',
+  ADD COLUMN max_discount   INTEGER                     NULL COMMENT '割引率で割り引くときの割引額の上限',
+  ADD COLUMN min_fare       INTEGER                     NOT NULL DEFAULT 0 COMMENT '使える最低運賃',
+  ADD COLUMN valid_from     DATETIME(6)                 NULL COMMENT '使用可能期間の開始',
+  ADD COLUMN valid_until    DATETIME(6)                 NULL COMMENT '使用可能期間の終了',
+  ADD COLUMN priority       INTEGER                     NOT NULL DEFAULT 0 COMMENT '優先度。大きいものから使う',
+  ADD COLUMN per_user_limit INTEGER                     NULL COMMENT '同じキャンペーンのクーポンを 1 人が使える回数';
+UPDATE coupons SET campaign = 'NEW2024', priority = 100, per_user_limit = 1 WHERE code = 'CP_NEW2024';
+UPDATE coupons SET campaign = 'INVITATION', priority = 50, per_user_limit = 1 WHERE code LIKE 'INV\_%';
+UPDATE coupons SET campaign = 'REWARD' WHERE code LIKE 'RWD\_%';
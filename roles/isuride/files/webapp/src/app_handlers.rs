@@ -1,30 +1,45 @@
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
 
 use async_stream::stream;
 use axum::extract::{Path, Query, State};
-use axum::http::StatusCode;
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::sse::Event;
 use axum::response::Sse;
 use axum_extra::extract::CookieJar;
-use dashmap::DashMap;
 use futures::Stream;
 use sqlx::MySqlPool;
-use tokio::sync::watch;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
 use tokio_stream::StreamExt as _;
 use tracing::{info, warn};
 use ulid::Ulid;
 
-use crate::models::{Chair, ChairLocation, Coupon, Owner, PaymentToken, Ride, RideStatus, User};
+use crate::chair_registry::ChairRegistry;
+use crate::coupons::{CouponChoice, INVITATION_COUPON, INVITER_REWARD_COUPON, NEW_USER_COUPON};
+use crate::events::{Audience, ChairStats, EventBus, RideEvent, RideSnapshot};
+use crate::models::{Chair, Coupon, DiscountType, Owner, PaymentToken, Ride, RideState, User};
+use crate::pricing::PricingEngine;
+use crate::quotes::FareQuote;
 use crate::{AppState, Coordinate, Error};
 
 pub fn app_routes(app_state: AppState) -> axum::Router<AppState> {
     let routes = axum::Router::new().route("/api/app/users", axum::routing::post(app_post_users));
 
     let authed_routes = axum::Router::new()
+        .route("/api/app/coupons", axum::routing::get(app_get_coupons))
         .route(
             "/api/app/payment-methods",
-            axum::routing::post(app_post_payment_methods),
+            axum::routing::get(app_get_payment_methods).post(app_post_payment_methods),
+        )
+        .route(
+            "/api/app/payment-methods/:payment_method_id",
+            axum::routing::delete(app_delete_payment_method),
+        )
+        .route(
+            "/api/app/payment-methods/:payment_method_id/default",
+            axum::routing::post(app_post_payment_method_default),
         )
         .route(
             "/api/app/rides",
@@ -38,10 +53,18 @@ pub fn app_routes(app_state: AppState) -> axum::Router<AppState> {
             "/api/app/rides/:ride_id/evaluation",
             axum::routing::post(app_post_ride_evaluation),
         )
+        .route(
+            "/api/app/rides/:ride_id/cancel",
+            axum::routing::post(app_post_ride_cancel),
+        )
         .route(
             "/api/app/notification",
             axum::routing::get(app_get_notification),
         )
+        .route(
+            "/api/app/notification/ack",
+            axum::routing::post(app_post_notification_ack),
+        )
         .route(
             "/api/app/nearby-chairs",
             axum::routing::get(app_get_nearby_chairs),
@@ -92,12 +115,7 @@ async fn app_post_users(
         .await?;
 
     // 初回登録キャンペーンのクーポンを付与
-    sqlx::query("INSERT INTO coupons (user_id, code, discount) VALUES (?, ?, ?)")
-        .bind(&user_id)
-        .bind("CP_NEW2024")
-        .bind(3000)
-        .execute(&mut *tx)
-        .await?;
+    crate::coupons::grant_coupon(&mut tx, &user_id, "CP_NEW2024", &NEW_USER_COUPON).await?;
 
     // 招待コードを使った登録
     if let Some(req_invitation_code) = req.invitation_code {
@@ -123,19 +141,24 @@ async fn app_post_users(
             };
 
             // 招待クーポン付与
-            sqlx::query("INSERT INTO coupons (user_id, code, discount) VALUES (?, ?, ?)")
-                .bind(&user_id)
-                .bind(format!("INV_{req_invitation_code}"))
-                .bind(1500)
-                .execute(&mut *tx)
-                .await?;
+            crate::coupons::grant_coupon(
+                &mut tx,
+                &user_id,
+                &format!("INV_{req_invitation_code}"),
+                &INVITATION_COUPON,
+            )
+            .await?;
             // 招待した人にもRewardを付与
-            sqlx::query("INSERT INTO coupons (user_id, code, discount) VALUES (?, CONCAT(?, '_', FLOOR(UNIX_TIMESTAMP(NOW(3))*1000)), ?)")
-                .bind(inviter.id)
-                .bind(format!("RWD_{req_invitation_code}"))
-                .bind(1000)
-                .execute(&mut *tx)
-                .await?;
+            crate::coupons::grant_coupon(
+                &mut tx,
+                &inviter.id,
+                &format!(
+                    "RWD_{req_invitation_code}_{}",
+                    chrono::Utc::now().timestamp_millis()
+                ),
+                &INVITER_REWARD_COUPON,
+            )
+            .await?;
         }
     }
 
@@ -159,6 +182,8 @@ async fn app_post_users(
 #[derive(Debug, serde::Deserialize)]
 struct AppPostPaymentMethodsRequest {
     token: String,
+    #[serde(default)]
+    is_default: bool,
 }
 
 async fn app_post_payment_methods(
@@ -166,12 +191,140 @@ async fn app_post_payment_methods(
     axum::Extension(user): axum::Extension<User>,
     axum::Json(req): axum::Json<AppPostPaymentMethodsRequest>,
 ) -> Result<StatusCode, Error> {
-    sqlx::query("INSERT INTO payment_tokens (user_id, token) VALUES (?, ?)")
-        .bind(user.id)
+    let mut tx = pool.begin().await?;
+
+    let payment_tokens = crate::payments::retrieve_payment_tokens(&mut *tx, &user.id).await?;
+    if payment_tokens
+        .iter()
+        .any(|payment_token| payment_token.token == req.token)
+    {
+        return Err(Error::Conflict("payment method already registered"));
+    }
+
+    // 最初に登録した支払い方法はデフォルトにする
+    let is_default = req.is_default || payment_tokens.is_empty();
+    if is_default {
+        sqlx::query("UPDATE payment_tokens SET is_default = FALSE WHERE user_id = ?")
+            .bind(&user.id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    sqlx::query("INSERT INTO payment_tokens (id, user_id, token, is_default) VALUES (?, ?, ?, ?)")
+        .bind(Ulid::new().to_string())
+        .bind(&user.id)
         .bind(req.token)
-        .execute(&pool)
+        .bind(is_default)
+        .execute(&mut *tx)
         .await?;
 
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, serde::Serialize)]
+struct AppGetPaymentMethodsResponse {
+    payment_methods: Vec<AppGetPaymentMethodsResponseItem>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct AppGetPaymentMethodsResponseItem {
+    id: String,
+    /// トークンそのものは返さず、見分けるための末尾だけを返す
+    token_suffix: String,
+    is_default: bool,
+    created_at: i64,
+}
+
+async fn app_get_payment_methods(
+    State(AppState { pool, .. }): State<AppState>,
+    axum::Extension(user): axum::Extension<User>,
+) -> Result<axum::Json<AppGetPaymentMethodsResponse>, Error> {
+    let payment_tokens = crate::payments::retrieve_payment_tokens(&pool, &user.id).await?;
+
+    Ok(axum::Json(AppGetPaymentMethodsResponse {
+        payment_methods: payment_tokens
+            .into_iter()
+            .map(|payment_token| {
+                let chars: Vec<char> = payment_token.token.chars().collect();
+                AppGetPaymentMethodsResponseItem {
+                    id: payment_token.id,
+                    token_suffix: chars[chars.len().saturating_sub(4)..].iter().collect(),
+                    is_default: payment_token.is_default,
+                    created_at: payment_token.created_at.timestamp_millis(),
+                }
+            })
+            .collect(),
+    }))
+}
+
+async fn app_delete_payment_method(
+    State(AppState { pool, .. }): State<AppState>,
+    axum::Extension(user): axum::Extension<User>,
+    Path((payment_method_id,)): Path<(String,)>,
+) -> Result<StatusCode, Error> {
+    let mut tx = pool.begin().await?;
+
+    let Some(payment_token): Option<PaymentToken> =
+        sqlx::query_as("SELECT * FROM payment_tokens WHERE id = ? AND user_id = ? FOR UPDATE")
+            .bind(&payment_method_id)
+            .bind(&user.id)
+            .fetch_optional(&mut *tx)
+            .await?
+    else {
+        return Err(Error::NotFound("payment method not found"));
+    };
+
+    sqlx::query("DELETE FROM payment_tokens WHERE id = ?")
+        .bind(&payment_token.id)
+        .execute(&mut *tx)
+        .await?;
+
+    // デフォルトを消したときは、残っている中で最初に登録したものをデフォルトにする
+    if payment_token.is_default {
+        if let Some(next) = crate::payments::retrieve_payment_tokens(&mut *tx, &user.id)
+            .await?
+            .into_iter()
+            .next()
+        {
+            sqlx::query("UPDATE payment_tokens SET is_default = TRUE WHERE id = ?")
+                .bind(&next.id)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn app_post_payment_method_default(
+    State(AppState { pool, .. }): State<AppState>,
+    axum::Extension(user): axum::Extension<User>,
+    Path((payment_method_id,)): Path<(String,)>,
+) -> Result<StatusCode, Error> {
+    let mut tx = pool.begin().await?;
+
+    let exists: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM payment_tokens WHERE id = ? AND user_id = ?)",
+    )
+    .bind(&payment_method_id)
+    .bind(&user.id)
+    .fetch_one(&mut *tx)
+    .await?;
+    if !exists {
+        return Err(Error::NotFound("payment method not found"));
+    }
+
+    sqlx::query("UPDATE payment_tokens SET is_default = (id = ?) WHERE user_id = ?")
+        .bind(&payment_method_id)
+        .bind(&user.id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
     Ok(StatusCode::NO_CONTENT)
 }
 
@@ -190,6 +343,8 @@ struct GetAppRidesResponseItem {
     evaluation: i32,
     requested_at: i64,
     completed_at: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payment_status: Option<String>,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -215,20 +370,11 @@ async fn app_get_rides(
     let mut items = Vec::with_capacity(rides.len());
     for ride in rides {
         let status = crate::get_latest_ride_status(&mut *tx, &ride.id).await?;
-        if status != "COMPLETED" {
+        if status != RideState::Completed {
             continue;
         }
 
-        let fare = calculate_discounted_fare(
-            &mut tx,
-            &user.id,
-            Some(&ride),
-            ride.pickup_latitude,
-            ride.pickup_longitude,
-            ride.destination_latitude,
-            ride.destination_longitude,
-        )
-        .await?;
+        let fare = calculate_discounted_fare(&mut tx, &ride).await?;
 
         let chair: Chair = sqlx::query_as("SELECT * FROM chairs WHERE id = ?")
             .bind(&ride.chair_id)
@@ -240,6 +386,14 @@ async fn app_get_rides(
             .fetch_one(&mut *tx)
             .await?;
 
+        let payment_status: Option<String> =
+            sqlx::query_scalar("SELECT status FROM payments WHERE ride_id = ?")
+                .bind(&ride.id)
+                .fetch_optional(&mut *tx)
+                .await?;
+        // 返金された分を差し引いた、実際に支払った額を返す
+        let fare = fare - crate::refunds::refunded_amount(&mut tx, &ride.id).await?;
+
         items.push(GetAppRidesResponseItem {
             id: ride.id,
             pickup_coordinate: Coordinate {
@@ -260,6 +414,7 @@ async fn app_get_rides(
             evaluation: ride.evaluation.unwrap(),
             requested_at: ride.created_at.timestamp_millis(),
             completed_at: ride.updated_at.timestamp_millis(),
+            payment_status,
         });
     }
 
@@ -268,28 +423,130 @@ async fn app_get_rides(
     Ok(axum::Json(GetAppRidesResponse { rides: items }))
 }
 
+#[derive(Debug, serde::Serialize)]
+struct AppGetCouponsResponse {
+    unused: Vec<AppGetCouponsResponseCoupon>,
+    used: Vec<AppGetCouponsResponseCoupon>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct AppGetCouponsResponseCoupon {
+    code: String,
+    /// signup (初回登録)、invitation (招待された)、reward (招待した)
+    origin: String,
+    discount_type: DiscountType,
+    /// FLAT なら割引額、PERCENTAGE なら割引率 (%)
+    discount: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_discount: Option<i32>,
+    min_fare: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    valid_until: Option<i64>,
+    /// 期限切れなどで今は使えない未使用のクーポンは false
+    available: bool,
+    /// クーポンを使ったライド
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ride_id: Option<String>,
+    created_at: i64,
+}
+
+/// 持っているクーポンの一覧。未使用のものは使われる順に並べる
+async fn app_get_coupons(
+    State(AppState { pool, .. }): State<AppState>,
+    axum::Extension(user): axum::Extension<User>,
+) -> Result<axum::Json<AppGetCouponsResponse>, Error> {
+    let coupons: Vec<Coupon> = sqlx::query_as(
+        "SELECT * FROM coupons WHERE user_id = ? ORDER BY priority DESC, created_at, code",
+    )
+    .bind(&user.id)
+    .fetch_all(&pool)
+    .await?;
+
+    let now = chrono::Utc::now();
+    let mut res = AppGetCouponsResponse {
+        unused: Vec::new(),
+        used: Vec::new(),
+    };
+    for coupon in coupons {
+        // 最低運賃の条件はライドごとに違うので、ここでは期限だけを見る
+        let available = coupon.is_applicable(coupon.min_fare, now);
+        let item = AppGetCouponsResponseCoupon {
+            origin: coupon.origin().to_owned(),
+            code: coupon.code,
+            discount_type: coupon.discount_type,
+            discount: coupon.discount,
+            max_discount: coupon.max_discount,
+            min_fare: coupon.min_fare,
+            valid_until: coupon.valid_until.map(|t| t.timestamp_millis()),
+            available,
+            ride_id: coupon.used_by,
+            created_at: coupon.created_at.timestamp_millis(),
+        };
+        if item.ride_id.is_some() {
+            res.used.push(item);
+        } else {
+            res.unused.push(item);
+        }
+    }
+
+    Ok(axum::Json(res))
+}
+
 #[derive(Debug, serde::Deserialize)]
 struct AppPostRidesRequest {
     pickup_coordinate: Coordinate,
     destination_coordinate: Coordinate,
+    /// 見積もりの ID。渡したときは見積もった運賃とクーポンでライドを作る
+    quote_id: Option<String>,
+    /// 使うクーポン。省略すると使えるクーポンのうち優先度の高いものを使う
+    coupon_code: Option<String>,
+    /// クーポンを使わない
+    #[serde(default)]
+    skip_coupon: bool,
 }
 
 #[derive(Debug, serde::Serialize)]
 struct AppPostRidesResponse {
     ride_id: String,
     fare: i32,
+    surge_multiplier: f64,
 }
 
 async fn app_post_rides(
     State(AppState {
         pool,
-        ride_status_notify_by_chair_id,
-        ride_status_notify_by_user_id,
+        event_bus,
+        matching_notify,
+        chair_registry,
+        quote_signer,
+        ..
     }): State<AppState>,
     axum::Extension(user): axum::Extension<User>,
     axum::Json(req): axum::Json<AppPostRidesRequest>,
 ) -> Result<(StatusCode, axum::Json<AppPostRidesResponse>), Error> {
     let ride_id = Ulid::new().to_string();
+    let coupon_choice = CouponChoice::from_request(req.coupon_code, req.skip_coupon)?;
+
+    let quote = match &req.quote_id {
+        Some(quote_id) => {
+            let quote = quote_signer.verify(quote_id)?;
+            // クーポンを選ぶなら見積もりのときと同じものを選んでいなければならない
+            let coupon_matches = match &coupon_choice {
+                CouponChoice::Auto => true,
+                CouponChoice::Skip => quote.coupon_code.is_none(),
+                CouponChoice::Code(code) => quote.coupon_code.as_ref() == Some(code),
+            };
+            if quote.user_id != user.id
+                || quote.pickup != req.pickup_coordinate
+                || quote.destination != req.destination_coordinate
+                || !coupon_matches
+            {
+                return Err(Error::BadRequest("quote does not match the request"));
+            }
+            Some(quote)
+        }
+        None => None,
+    };
 
     let mut tx = pool.begin().await?;
 
@@ -301,7 +558,7 @@ async fn app_post_rides(
     let mut continuing_ride_count = 0;
     for ride in rides {
         let status = crate::get_latest_ride_status(&mut *tx, &ride.id).await?;
-        if status != "COMPLETED" {
+        if !status.is_finished() {
             continuing_ride_count += 1;
         }
     }
@@ -310,109 +567,82 @@ async fn app_post_rides(
         return Err(Error::Conflict("ride already exists"));
     }
 
-    sqlx::query("INSERT INTO rides (id, user_id, pickup_latitude, pickup_longitude, destination_latitude, destination_longitude) VALUES (?, ?, ?, ?, ?, ?)")
+    // サージの倍率はこのライド自身を数える前に決めて、ライドに固定する。
+    // 運賃もここで固定し、椅子が決まっても変えずにそのまま請求する
+    let (fare, surge_multiplier, coupon_code) = match &quote {
+        Some(quote) => (
+            quote.fare,
+            quote.surge_multiplier,
+            quote.coupon_code.clone(),
+        ),
+        None => {
+            let quoted = quote_fare(
+                &mut tx,
+                &chair_registry,
+                &user.id,
+                &coupon_choice,
+                true,
+                req.pickup_coordinate,
+                req.destination_coordinate,
+            )
+            .await?;
+            (quoted.fare, quoted.surge_multiplier, quoted.coupon_code)
+        }
+    };
+
+    let result = sqlx::query("INSERT INTO rides (id, user_id, pickup_latitude, pickup_longitude, destination_latitude, destination_longitude, surge_multiplier, quoted_fare, quote_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")
         .bind(&ride_id)
         .bind(&user.id)
         .bind(req.pickup_coordinate.latitude)
         .bind(req.pickup_coordinate.longitude)
         .bind(req.destination_coordinate.latitude)
         .bind(req.destination_coordinate.longitude)
+        .bind(surge_multiplier)
+        .bind(fare)
+        .bind(quote.as_ref().map(|quote| &quote.id))
         .execute(&mut *tx)
-        .await?;
+        .await;
+    match result {
+        Ok(_) => {}
+        // rides_quote_id に引っかかったら、その見積もりはもう使われている
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            return Err(Error::Conflict("quote has already been used"));
+        }
+        Err(e) => return Err(e.into()),
+    }
 
-    sqlx::query("INSERT INTO ride_statuses (id, ride_id, status) VALUES (?, ?, ?)")
-        .bind(Ulid::new().to_string())
-        .bind(&ride_id)
-        .bind("MATCHING")
-        .execute(&mut *tx)
-        .await?;
+    let ride_status_id = crate::insert_ride_status(&mut tx, &ride_id, RideState::Matching).await?;
 
-    let ride_count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM rides WHERE user_id = ?")
+    if let Some(coupon_code) = &coupon_code {
+        let result = sqlx::query(
+            "UPDATE coupons SET used_by = ? WHERE user_id = ? AND code = ? AND used_by IS NULL",
+        )
+        .bind(&ride_id)
         .bind(&user.id)
-        .fetch_one(&mut *tx)
+        .bind(coupon_code)
+        .execute(&mut *tx)
         .await?;
-
-    if ride_count == 1 {
-        // 初回利用で、初回利用クーポンがあれば必ず使う
-        let coupon: Option<Coupon> = sqlx::query_as("SELECT * FROM coupons WHERE user_id = ? AND code = 'CP_NEW2024' AND used_by IS NULL FOR UPDATE")
-            .bind(&user.id)
-            .fetch_optional(&mut *tx)
-            .await?;
-        if coupon.is_some() {
-            sqlx::query("UPDATE coupons SET used_by = ? WHERE user_id = ? AND code = 'CP_NEW2024'")
-                .bind(&ride_id)
-                .bind(&user.id)
-                .execute(&mut *tx)
-                .await?;
-        } else {
-            // 無ければ他のクーポンを付与された順番に使う
-            let coupon: Option<Coupon> = sqlx::query_as("SELECT * FROM coupons WHERE user_id = ? AND used_by IS NULL ORDER BY created_at LIMIT 1 FOR UPDATE")
-                .bind(&user.id)
-                .fetch_optional(&mut *tx)
-                .await?;
-            if let Some(coupon) = coupon {
-                sqlx::query("UPDATE coupons SET used_by = ? WHERE user_id = ? AND code = ?")
-                    .bind(&ride_id)
-                    .bind(&user.id)
-                    .bind(coupon.code)
-                    .execute(&mut *tx)
-                    .await?;
-            }
-        }
-    } else {
-        // 他のクーポンを付与された順番に使う
-        let coupon: Option<Coupon> = sqlx::query_as("SELECT * FROM coupons WHERE user_id = ? AND used_by IS NULL ORDER BY created_at LIMIT 1 FOR UPDATE")
-                .bind(&user.id)
-                .fetch_optional(&mut *tx)
-                .await?;
-        if let Some(coupon) = coupon {
-            sqlx::query("UPDATE coupons SET used_by = ? WHERE user_id = ? AND code = ?")
-                .bind(&ride_id)
-                .bind(&user.id)
-                .bind(coupon.code)
-                .execute(&mut *tx)
-                .await?;
+        // 見積もりで使ったクーポンがもう使われていれば、固定した運賃は保証できない
+        if result.rows_affected() == 0 {
+            return Err(Error::Conflict("coupon is no longer available"));
         }
     }
 
-    let ride: Ride = sqlx::query_as("SELECT * FROM rides WHERE id = ?")
-        .bind(&ride_id)
-        .fetch_one(&mut *tx)
-        .await?;
+    tx.commit().await?;
 
-    let fare = calculate_discounted_fare(
-        &mut tx,
-        &user.id,
-        Some(&ride),
-        req.pickup_coordinate.latitude,
-        req.pickup_coordinate.longitude,
-        req.destination_coordinate.latitude,
-        req.destination_coordinate.longitude,
-    )
-    .await?;
+    event_bus
+        .publish_status_changed(&pool, &ride_id, ride_status_id, None, RideState::Matching)
+        .await;
 
-    tx.commit().await?;
+    matching_notify.notify_one();
 
-    if let Some(chair_id) = ride.chair_id {
-        ride_status_notify_by_chair_id
-            .entry(chair_id.clone())
-            .or_insert_with(|| watch::channel(Ulid::new()))
-            .0
-            .send(Ulid::new())
-            .unwrap();
-        info!(chair_id, "notify chair change");
-    }
-    ride_status_notify_by_user_id
-        .entry(ride.user_id.clone())
-        .or_insert_with(|| watch::channel(Ulid::new()))
-        .0
-        .send(Ulid::new())
-        .unwrap();
-
-    info!(user_id = ride.user_id, "notify user change");
     Ok((
         StatusCode::ACCEPTED,
-        axum::Json(AppPostRidesResponse { ride_id, fare }),
+        axum::Json(AppPostRidesResponse {
+            ride_id,
+            fare,
+            surge_multiplier,
+        }),
     ))
 }
 
@@ -420,45 +650,117 @@ async fn app_post_rides(
 struct AppPostRidesEstimatedFareRequest {
     pickup_coordinate: Coordinate,
     destination_coordinate: Coordinate,
+    coupon_code: Option<String>,
+    #[serde(default)]
+    skip_coupon: bool,
 }
 
 #[derive(Debug, serde::Serialize)]
 struct AppPostRidesEstimatedFareResponse {
     fare: i32,
     discount: i32,
+    /// 割引に使うクーポン
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coupon_code: Option<String>,
+    /// 今ライドを作ると固定されるサージの倍率
+    surge_multiplier: f64,
+    /// `POST /api/app/rides` に渡すと、期限までに 1 回だけ見積もった運賃とクーポンでライドを作れる
+    quote_id: String,
+    quote_expires_at: i64,
 }
 
 async fn app_post_rides_estimated_fare(
-    State(AppState { pool, .. }): State<AppState>,
+    State(AppState {
+        pool,
+        chair_registry,
+        quote_signer,
+        ..
+    }): State<AppState>,
     axum::Extension(user): axum::Extension<User>,
     axum::Json(req): axum::Json<AppPostRidesEstimatedFareRequest>,
 ) -> Result<axum::Json<AppPostRidesEstimatedFareResponse>, Error> {
+    let coupon_choice = CouponChoice::from_request(req.coupon_code, req.skip_coupon)?;
+
     let mut tx = pool.begin().await?;
 
-    let discounted = calculate_discounted_fare(
+    let QuotedFare {
+        fare,
+        discount,
+        surge_multiplier,
+        coupon_code,
+    } = quote_fare(
         &mut tx,
+        &chair_registry,
         &user.id,
-        None,
-        req.pickup_coordinate.latitude,
-        req.pickup_coordinate.longitude,
-        req.destination_coordinate.latitude,
-        req.destination_coordinate.longitude,
+        &coupon_choice,
+        false,
+        req.pickup_coordinate,
+        req.destination_coordinate,
     )
     .await?;
 
     tx.commit().await?;
 
+    let quote = FareQuote::new(
+        user.id,
+        req.pickup_coordinate,
+        req.destination_coordinate,
+        fare,
+        surge_multiplier,
+        coupon_code.clone(),
+    );
+
     Ok(axum::Json(AppPostRidesEstimatedFareResponse {
-        fare: discounted,
-        discount: crate::calculate_fare(
-            req.pickup_coordinate.latitude,
-            req.pickup_coordinate.longitude,
-            req.destination_coordinate.latitude,
-            req.destination_coordinate.longitude,
-        ) - discounted,
+        fare,
+        discount,
+        coupon_code,
+        surge_multiplier,
+        quote_id: quote_signer.sign(&quote),
+        quote_expires_at: quote.expires_at,
     }))
 }
 
+/// 椅子が決まる前に固定する運賃
+struct QuotedFare {
+    /// 割引後の運賃
+    fare: i32,
+    discount: i32,
+    surge_multiplier: f64,
+    coupon_code: Option<String>,
+}
+
+/// 今のサージの倍率と、選ばれたクーポンか使えるクーポンのうち優先度の高いものの割引で運賃を出す。
+/// 見積もりとライドの作成で同じ計算をする。`for_update` のときは使うクーポンをロックする
+async fn quote_fare(
+    tx: &mut sqlx::MySqlConnection,
+    chair_registry: &ChairRegistry,
+    user_id: &str,
+    coupon_choice: &CouponChoice,
+    for_update: bool,
+    pickup: Coordinate,
+    destination: Coordinate,
+) -> Result<QuotedFare, Error> {
+    let pricing = PricingEngine::load(&mut *tx).await?;
+    let surge_multiplier = pricing
+        .surge_multiplier(&mut *tx, chair_registry, pickup)
+        .await?;
+    let fare = pricing.fare(None, surge_multiplier, pickup, destination);
+    let coupon =
+        crate::coupons::choose_coupon(&mut *tx, user_id, fare, coupon_choice, for_update).await?;
+    let metered_fare = pricing.metered_fare(None, surge_multiplier, pickup, destination);
+    let discount = coupon
+        .as_ref()
+        .map(|coupon| coupon.discount_for(metered_fare))
+        .unwrap_or(0);
+    let discounted = pricing.discounted_fare(None, surge_multiplier, pickup, destination, discount);
+    Ok(QuotedFare {
+        fare: discounted,
+        discount: fare - discounted,
+        surge_multiplier,
+        coupon_code: coupon.map(|coupon| coupon.code),
+    })
+}
+
 #[derive(Debug, serde::Deserialize)]
 struct AppPostRideEvaluationRequest {
     evaluation: i32,
@@ -473,8 +775,9 @@ struct AppPostRideEvaluationResponse {
 async fn app_post_ride_evaluation(
     State(AppState {
         pool,
-        ride_status_notify_by_chair_id,
-        ride_status_notify_by_user_id,
+        event_bus,
+        matching_notify,
+        payment_notify,
         ..
     }): State<AppState>,
     Path((ride_id,)): Path<(String,)>,
@@ -493,29 +796,33 @@ async fn app_post_ride_evaluation(
     else {
         return Err(Error::NotFound("ride not found"));
     };
-    let status = crate::get_latest_ride_status(&mut *tx, &ride.id).await?;
+    let transition = crate::transition_ride_status(&mut tx, &ride.id, RideState::Completed).await?;
 
-    if status != "ARRIVED" {
-        return Err(Error::BadRequest("not arrived yet"));
-    }
-
-    let result = sqlx::query("UPDATE rides SET evaluation = ? WHERE id = ?")
-        .bind(req.evaluation)
-        .bind(&ride_id)
-        .execute(&mut *tx)
+    // オーナーの売上は、担当した椅子のモデルが分かる完了時に固定する
+    let chair_model: String = sqlx::query_scalar("SELECT model FROM chairs WHERE id = ?")
+        .bind(&ride.chair_id)
+        .fetch_one(&mut *tx)
         .await?;
+    let (sale, surge_sale) = PricingEngine::load(&mut tx).await?.sale(
+        &chair_model,
+        ride.surge_multiplier,
+        ride.pickup_coordinate(),
+        ride.destination_coordinate(),
+    );
+
+    let result =
+        sqlx::query("UPDATE rides SET evaluation = ?, sale = ?, surge_sale = ? WHERE id = ?")
+            .bind(req.evaluation)
+            .bind(sale)
+            .bind(surge_sale)
+            .bind(&ride_id)
+            .execute(&mut *tx)
+            .await?;
     let count = result.rows_affected();
     if count == 0 {
         return Err(Error::NotFound("ride not found"));
     }
 
-    sqlx::query("INSERT INTO ride_statuses (id, ride_id, status) VALUES (?, ?, ?)")
-        .bind(Ulid::new().to_string())
-        .bind(&ride_id)
-        .bind("COMPLETED")
-        .execute(&mut *tx)
-        .await?;
-
     let Some(ride): Option<Ride> = sqlx::query_as("SELECT * FROM rides WHERE id = ?")
         .bind(&ride_id)
         .fetch_optional(&mut *tx)
@@ -524,71 +831,105 @@ async fn app_post_ride_evaluation(
         return Err(Error::NotFound("ride not found"));
     };
 
-    let Some(payment_token): Option<PaymentToken> =
-        sqlx::query_as("SELECT * FROM payment_tokens WHERE user_id = ?")
-            .bind(&ride.user_id)
-            .fetch_optional(&mut *tx)
-            .await?
-    else {
+    // どの支払い方法で請求するかは決済ワーカーが決める
+    let payment_tokens = crate::payments::retrieve_payment_tokens(&mut *tx, &ride.user_id).await?;
+    if payment_tokens.is_empty() {
         return Err(Error::BadRequest("payment token not registered"));
-    };
-
-    let fare = calculate_discounted_fare(
-        &mut tx,
-        &ride.user_id,
-        Some(&ride),
-        ride.pickup_latitude,
-        ride.pickup_longitude,
-        ride.destination_latitude,
-        ride.destination_longitude,
-    )
-    .await?;
+    }
 
-    let payment_gateway_url: String =
-        sqlx::query_scalar("SELECT value FROM settings WHERE name = 'payment_gateway_url'")
-            .fetch_one(&mut *tx)
-            .await?;
+    let fare = calculate_discounted_fare(&mut tx, &ride).await?;
 
-    crate::payment_gateway::request_payment_gateway_post_payment(
-        &payment_gateway_url,
-        &payment_token.token,
-        &crate::payment_gateway::PaymentGatewayPostPaymentRequest { amount: fare },
-    )
-    .await?;
+    // 決済サービスへの請求はトランザクションの外で決済ワーカーが行う
+    crate::payments::enqueue_payment(&mut tx, &ride.id, &ride.user_id, fare).await?;
 
     tx.commit().await?;
 
-    if let Some(chair_id) = ride.chair_id {
-        ride_status_notify_by_chair_id
-            .entry(chair_id.clone())
-            .or_insert_with(|| watch::channel(Ulid::new()))
-            .0
-            .send(Ulid::new())
-            .unwrap();
-        info!(chair_id, "notify chair change");
-    }
-    ride_status_notify_by_user_id
-        .entry(ride.user_id.clone())
-        .or_insert_with(|| watch::channel(Ulid::new()))
-        .0
-        .send(Ulid::new())
-        .unwrap();
-    info!(user_id = ride.user_id, "notify user change");
+    payment_notify.notify_one();
+
+    event_bus
+        .publish_status_changed(
+            &pool,
+            &ride.id,
+            transition.ride_status_id,
+            Some(transition.previous_ride_status_id),
+            RideState::Completed,
+        )
+        .await;
+
+    matching_notify.notify_one();
+
     Ok(axum::Json(AppPostRideEvaluationResponse {
         fare,
         completed_at: ride.updated_at.timestamp_millis(),
     }))
 }
 
+async fn app_post_ride_cancel(
+    State(AppState {
+        pool,
+        event_bus,
+        matching_notify,
+        chair_registry,
+        ..
+    }): State<AppState>,
+    axum::Extension(user): axum::Extension<User>,
+    Path((ride_id,)): Path<(String,)>,
+) -> Result<StatusCode, Error> {
+    let mut tx = pool.begin().await?;
+
+    let Some(ride): Option<Ride> = sqlx::query_as("SELECT * FROM rides WHERE id = ? FOR UPDATE")
+        .bind(&ride_id)
+        .fetch_optional(&mut *tx)
+        .await?
+    else {
+        return Err(Error::NotFound("ride not found"));
+    };
+    if ride.user_id != user.id {
+        return Err(Error::NotFound("ride not found"));
+    }
+
+    // 椅子が迎えに来るまではキャンセルできる
+    let transition = crate::transition_ride_status(&mut tx, &ride.id, RideState::Canceled).await?;
+
+    // 使ったクーポンは未使用に戻す
+    sqlx::query("UPDATE coupons SET used_by = NULL WHERE used_by = ?")
+        .bind(&ride.id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    // 椅子が通知を受け取れなくても、すぐにマッチング対象に戻す
+    if let Some(chair_id) = &ride.chair_id {
+        chair_registry.release(chair_id, &ride.id);
+        matching_notify.notify_one();
+    }
+
+    event_bus
+        .publish_status_changed(
+            &pool,
+            &ride.id,
+            transition.ride_status_id,
+            Some(transition.previous_ride_status_id),
+            RideState::Canceled,
+        )
+        .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
 #[derive(Debug, serde::Serialize)]
 struct AppGetNotificationResponseData {
     ride_id: String,
     pickup_coordinate: Coordinate,
     destination_coordinate: Coordinate,
     fare: i32,
-    status: String,
+    status: RideState,
     #[serde(skip_serializing_if = "Option::is_none")]
     chair: Option<AppGetNotificationResponseChair>,
+    /// ENROUTE なら乗車位置まで、CARRYING なら目的地までの椅子からのマンハッタン距離
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remaining_distance: Option<i32>,
     created_at: i64,
     updated_at: i64,
 }
@@ -598,207 +939,289 @@ struct AppGetNotificationResponseChair {
     id: String,
     name: String,
     model: String,
-    stats: AppGetNotificationResponseChairStats,
+    stats: ChairStats,
+    /// 椅子が向かっている間と乗せている間だけ送る
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_coordinate: Option<Coordinate>,
 }
 
-#[derive(Debug, serde::Serialize)]
-struct AppGetNotificationResponseChairStats {
-    total_rides_count: i32,
-    total_evaluation_avg: f64,
+/// 同じユーザーに椅子の位置を送る最短の間隔
+const CHAIR_LOCATION_NOTIFICATION_INTERVAL: Duration = Duration::from_secs(1);
+
+fn app_notification_data(
+    ride: &RideSnapshot,
+    status: RideState,
+    chair_location: Option<Coordinate>,
+) -> AppGetNotificationResponseData {
+    let target = match status {
+        RideState::Enroute => Some(ride.pickup),
+        RideState::Carrying => Some(ride.destination),
+        _ => None,
+    };
+    let chair_location = chair_location.filter(|_| target.is_some());
+    let remaining_distance = target.zip(chair_location).map(|(target, location)| {
+        crate::calculate_distance(
+            location.latitude,
+            location.longitude,
+            target.latitude,
+            target.longitude,
+        )
+    });
+    AppGetNotificationResponseData {
+        ride_id: ride.ride_id.clone(),
+        pickup_coordinate: ride.pickup,
+        destination_coordinate: ride.destination,
+        fare: ride.fare,
+        status,
+        chair: ride
+            .chair
+            .as_ref()
+            .map(|chair| AppGetNotificationResponseChair {
+                id: chair.id.clone(),
+                name: chair.name.clone(),
+                model: chair.model.clone(),
+                stats: chair.stats,
+                current_coordinate: chair_location,
+            }),
+        remaining_distance,
+        created_at: ride.created_at,
+        updated_at: ride.updated_at,
+    }
 }
 
+/// 通知と、その SSE のイベントの ID。椅子の位置だけが変わったときは ID を付けない
+type AppNotification = (Option<String>, AppGetNotificationResponseData);
+
 fn poll_notification(
-    mut user_notification: watch::Receiver<Ulid>,
-    chair_notify: Arc<DashMap<String, (watch::Sender<Ulid>, watch::Receiver<Ulid>)>>,
+    mut events: broadcast::Receiver<RideEvent>,
+    event_bus: Arc<EventBus>,
     pool: MySqlPool,
+    chair_registry: Arc<ChairRegistry>,
     user_id: String,
-) -> impl Stream<Item = Result<Option<AppGetNotificationResponseData>, Error>> {
+    last_event_id: Option<String>,
+) -> impl Stream<Item = Result<Option<AppNotification>, Error>> {
     info!(user_id, "open user notification channel");
     stream! {
-        loop {
-            let mut tx = pool.begin().await?;
-
-            let Some(ride): Option<Ride> = sqlx::query_as(
-                "SELECT * FROM rides WHERE user_id = ? ORDER BY created_at DESC LIMIT 1",
+        // 最後に送った (ライドID, 状態ID)
+        let mut last_sent: Option<(String, String)> = None;
+        let mut sent_ride_status_ids = HashSet::new();
+        let mut resume_after = last_event_id;
+        // 最後に送ったライドと状態。椅子の位置が変わったときはこれに位置を添えて送る
+        let mut current: Option<(Arc<RideSnapshot>, RideState)> = None;
+        let mut chair_location: Option<Coordinate> = None;
+        let mut last_location_sent_at: Option<Instant> = None;
+        // 間引いた位置があれば、間隔が空いたときに送る
+        let mut location_flush_at: Option<Instant> = None;
+
+        // 購読を始めたときと、イベントを取りこぼしたときだけ DB から読み直す
+        'reload: loop {
+            let ride_id: Option<String> = sqlx::query_scalar(
+                "SELECT id FROM rides WHERE user_id = ? ORDER BY created_at DESC LIMIT 1",
             )
             .bind(&user_id)
-            .fetch_optional(&mut *tx)
-            .await?
-            else {
-                info!(user_id, "no ride found");
-                tx.commit().await?;
-                yield Ok(None);
-                // 次の更新が来るまで待機
-                user_notification.changed() .await.unwrap();
-                info!(user_id, "got new ride");
-                continue;
-            };
-
-            let yet_sent_ride_status: Option<RideStatus> = sqlx::query_as("SELECT * FROM ride_statuses WHERE ride_id = ? AND app_sent_at IS NULL ORDER BY created_at ASC LIMIT 1")
-            .bind(&ride.id)
-            .fetch_optional(&mut *tx)
-            .await?;
-            let (ride_status_id, status) = if let Some(yet_sent_ride_status) = yet_sent_ride_status {
-                (Some(yet_sent_ride_status.id), yet_sent_ride_status.status)
-            } else {
-                (None, crate::get_latest_ride_status(&mut *tx, &ride.id).await?,)
-            };
-
-            let fare = calculate_discounted_fare(
-                &mut tx,
-                &user_id,
-                Some(&ride),
-                ride.pickup_latitude,
-                ride.pickup_longitude,
-                ride.destination_latitude,
-                ride.destination_longitude,
-            )
+            .fetch_optional(&pool)
             .await?;
-
-            let mut data = AppGetNotificationResponseData {
-                ride_id: ride.id,
-                pickup_coordinate: Coordinate {
-                    latitude: ride.pickup_latitude,
-                    longitude: ride.pickup_longitude,
-                },
-                destination_coordinate: Coordinate {
-                    latitude: ride.destination_latitude,
-                    longitude: ride.destination_longitude,
-                },
-                fare,
-                status,
-                chair: None,
-                created_at: ride.created_at.timestamp_millis(),
-                updated_at: ride.updated_at.timestamp_millis(),
-            };
-
-            if let Some(chair_id) = ride.chair_id.clone() {
-                let chair: Chair = sqlx::query_as("SELECT * FROM chairs WHERE id = ?")
-                    .bind(chair_id)
-                    .fetch_one(&mut *tx)
+            match ride_id {
+                None if last_sent.is_none() => {
+                    info!(user_id, "no ride found");
+                    yield Ok(None);
+                }
+                None => {}
+                Some(ride_id) => {
+                    let after = resume_after.take().or_else(|| {
+                        last_sent
+                            .as_ref()
+                            .filter(|(sent_ride_id, _)| *sent_ride_id == ride_id)
+                            .map(|(_, ride_status_id)| ride_status_id.clone())
+                    });
+                    let mut pending = event_bus.load_pending_ride_statuses(
+                        &pool,
+                        Audience::App,
+                        &ride_id,
+                        after.as_deref(),
+                    )
                     .await?;
-
-                let stats = get_chair_stats(&mut tx, &chair.id).await?;
-
-                data.chair = Some(AppGetNotificationResponseChair {
-                    id: chair.id,
-                    name: chair.name,
-                    model: chair.model,
-                    stats,
-                });
+                    // 接続した直後は、送るものが無くても今の状態を送る
+                    if pending.pending.is_empty() && last_sent.is_none() {
+                        pending.pending.extend(pending.latest);
+                    }
+                    let ride = Arc::new(pending.ride);
+                    chair_location = ride
+                        .chair_id()
+                        .and_then(|chair_id| chair_registry.get(chair_id)?.location);
+                    for ride_status in pending.pending {
+                        if !sent_ride_status_ids.insert(ride_status.id.clone()) {
+                            continue;
+                        }
+                        let data =
+                            app_notification_data(&ride, ride_status.status, chair_location);
+                        last_sent = Some((ride_id.clone(), ride_status.id.clone()));
+                        current = Some((ride.clone(), ride_status.status));
+                        info!(user_id, status = data.status.as_str(), "send sse");
+                        yield Ok(Some((Some(ride_status.id), data)));
+                    }
+                }
             }
 
-            if let Some(ride_status_id) = ride_status_id {
-                sqlx::query("UPDATE ride_statuses SET app_sent_at = CURRENT_TIMESTAMP(6) WHERE id = ?")
-                    .bind(ride_status_id)
-                    .execute(&mut *tx)
-                    .await?;
+            loop {
+                let received = match location_flush_at {
+                    Some(flush_at) => tokio::select! {
+                        received = events.recv() => Some(received),
+                        _ = tokio::time::sleep_until(flush_at) => None,
+                    },
+                    None => Some(events.recv().await),
+                };
+                let Some(received) = received else {
+                    location_flush_at = None;
+                    if let Some((ride, status)) = &current {
+                        if matches!(status, RideState::Enroute | RideState::Carrying) {
+                            last_location_sent_at = Some(Instant::now());
+                            yield Ok(Some((None, app_notification_data(ride, *status, chair_location))));
+                        }
+                    }
+                    continue;
+                };
+                let (ride_status_id, ride, status) = match received {
+                    Ok(RideEvent::StatusChanged { ride_status_id, previous_ride_status_id, status, ride }) => {
+                        if sent_ride_status_ids.contains(&ride_status_id) {
+                            continue;
+                        }
+                        // 直前の状態を送っていなければ、順番を守るために DB から読み直す
+                        if let Some((sent_ride_id, sent_ride_status_id)) = &last_sent {
+                            if *sent_ride_id == ride.ride_id
+                                && previous_ride_status_id.as_ref() != Some(sent_ride_status_id)
+                            {
+                                warn!(user_id, ride_status_id, "user notification out of order");
+                                continue 'reload;
+                            }
+                        }
+                        sent_ride_status_ids.insert(ride_status_id.clone());
+                        (ride_status_id, ride, status)
+                    }
+                    Ok(RideEvent::RideAssigned { ride_status_id, status, ride, .. }) => {
+                        (ride_status_id, ride, status)
+                    }
+                    Ok(RideEvent::LocationUpdated { chair_id, location, .. }) => {
+                        let Some((ride, status)) = &current else {
+                            continue;
+                        };
+                        if ride.chair_id() != Some(chair_id.as_str()) {
+                            continue;
+                        }
+                        chair_location = Some(location);
+                        if !matches!(status, RideState::Enroute | RideState::Carrying) {
+                            continue;
+                        }
+                        // 椅子は頻繁に位置を送ってくるので、間引いて最新の位置だけを送る
+                        if let Some(sent_at) = last_location_sent_at {
+                            if sent_at.elapsed() < CHAIR_LOCATION_NOTIFICATION_INTERVAL {
+                                location_flush_at
+                                    .get_or_insert(sent_at + CHAIR_LOCATION_NOTIFICATION_INTERVAL);
+                                continue;
+                            }
+                        }
+                        location_flush_at = None;
+                        last_location_sent_at = Some(Instant::now());
+                        yield Ok(Some((None, app_notification_data(ride, *status, chair_location))));
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(user_id, skipped, "user notification lagged");
+                        continue 'reload;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                // 別のライドに移ったら前の椅子の位置は使わない
+                if current.as_ref().is_none_or(|(current_ride, _)| {
+                    current_ride.ride_id != ride.ride_id || current_ride.chair_id() != ride.chair_id()
+                }) {
+                    chair_location = ride
+                        .chair_id()
+                        .and_then(|chair_id| chair_registry.get(chair_id)?.location);
+                }
+                let data = app_notification_data(&ride, status, chair_location);
+                // 状態の通知に最新の位置も載るので、間引いた位置を別に送る必要はない
+                location_flush_at = None;
+                last_sent = Some((data.ride_id.clone(), ride_status_id.clone()));
+                current = Some((ride, status));
+                info!(user_id, status = data.status.as_str(), "send sse");
+                yield Ok(Some((Some(ride_status_id), data)));
             }
-
-
-
-            tx.commit().await?;
-
-            if let Some(chair_id) = ride.chair_id.clone() {
-                chair_notify
-                    .entry(chair_id.clone())
-                    .or_insert_with(|| watch::channel(Ulid::new()))
-                    .0
-                    .send(Ulid::new())
-                    .unwrap();
-                info!(chair_id, "notify chair change");
-            }
-            info!(user_id, status=data.status, "send sse");
-            yield Ok(Some(data));
-            user_notification.changed().await.unwrap();
         }
     }
 }
 
+/// SSE のイベントの ID は ride_statuses の ID。
+/// 再接続時の `Last-Event-ID` までを受け取り済みとみなし、その後の状態から順に送り直す
 async fn app_get_notification(
     State(AppState {
         pool,
-        ride_status_notify_by_user_id,
-        ride_status_notify_by_chair_id,
+        event_bus,
+        chair_registry,
         ..
     }): State<AppState>,
     axum::Extension(user): axum::Extension<User>,
-) -> Sse<impl Stream<Item = Result<Event, Error>>> {
-    let user_notification = ride_status_notify_by_user_id
-        .entry(user.id.clone())
-        .or_insert_with(|| watch::channel(Ulid::new()))
-        .1
-        .clone();
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Error>>>, Error> {
+    // DB から読み直す前に購読しておき、その間に起きたイベントを取りこぼさないようにする
+    let events = event_bus.subscribe_user(&user.id);
+
+    let last_event_id = last_event_id(&headers);
+    if let Some(last_event_id) = &last_event_id {
+        crate::events::acknowledge_ride_statuses(&pool, Audience::App, &user.id, last_event_id)
+            .await?;
+    }
 
     let stream = poll_notification(
-        user_notification,
-        ride_status_notify_by_chair_id,
+        events,
+        event_bus.clone(),
         pool.clone(),
+        chair_registry,
         user.id.clone(),
+        last_event_id,
     );
     let stream = stream.map(|result| match result {
-        Ok(data) => Ok(Event::default().json_data(&data).unwrap()),
+        Ok(Some((ride_status_id, data))) => {
+            // ID の無いイベントでは、クライアントの Last-Event-ID は変わらない
+            let event = match ride_status_id {
+                Some(ride_status_id) => Event::default().id(ride_status_id),
+                None => Event::default(),
+            };
+            Ok(event.json_data(&data).unwrap())
+        }
+        Ok(None) => Ok(Event::default().json_data(None::<()>).unwrap()),
         Err(e) => {
             warn!(e = e.to_string(), "error happend");
             Err(e)
         }
     });
     info!("return sse");
-    Sse::new(stream.throttle(Duration::from_millis(300)))
+    Ok(Sse::new(stream.throttle(Duration::from_millis(300))))
 }
 
-async fn get_chair_stats(
-    tx: &mut sqlx::MySqlConnection,
-    chair_id: &str,
-) -> Result<AppGetNotificationResponseChairStats, Error> {
-    let rides: Vec<Ride> =
-        sqlx::query_as("SELECT * FROM rides WHERE chair_id = ? ORDER BY updated_at DESC")
-            .bind(chair_id)
-            .fetch_all(&mut *tx)
-            .await?;
-
-    let mut total_ride_count = 0;
-    let mut total_evaluation = 0.0;
-    for ride in rides {
-        let ride_statuses: Vec<RideStatus> =
-            sqlx::query_as("SELECT * FROM ride_statuses WHERE ride_id = ? ORDER BY created_at")
-                .bind(&ride.id)
-                .fetch_all(&mut *tx)
-                .await?;
+pub(crate) fn last_event_id(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty())
+        .map(ToOwned::to_owned)
+}
 
-        if !ride_statuses
-            .iter()
-            .any(|status| status.status == "ARRIVED")
-        {
-            continue;
-        }
-        if !ride_statuses
-            .iter()
-            .any(|status| (status.status == "CARRYING"))
-        {
-            continue;
-        }
-        let is_completed = ride_statuses
-            .iter()
-            .any(|status| status.status == "COMPLETED");
-        if !is_completed {
-            continue;
-        }
+#[derive(Debug, serde::Deserialize)]
+pub(crate) struct PostNotificationAckRequest {
+    pub id: String,
+}
 
-        total_ride_count += 1;
-        total_evaluation += ride.evaluation.unwrap() as f64;
+/// 通知を受け取ったことを確認し、それより前の状態も送信済みにする
+async fn app_post_notification_ack(
+    State(AppState { pool, .. }): State<AppState>,
+    axum::Extension(user): axum::Extension<User>,
+    axum::Json(req): axum::Json<PostNotificationAckRequest>,
+) -> Result<StatusCode, Error> {
+    if !crate::events::acknowledge_ride_statuses(&pool, Audience::App, &user.id, &req.id).await? {
+        return Err(Error::NotFound("notification not found"));
     }
-
-    let total_evaluation_avg = if total_ride_count > 0 {
-        total_evaluation / total_ride_count as f64
-    } else {
-        0.0
-    };
-
-    Ok(AppGetNotificationResponseChairStats {
-        total_rides_count: total_ride_count,
-        total_evaluation_avg,
-    })
+    Ok(StatusCode::NO_CONTENT)
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -806,6 +1229,7 @@ struct AppGetNearbyChairsQuery {
     latitude: i32,
     longitude: i32,
     distance: Option<i32>,
+    limit: Option<usize>,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -823,7 +1247,7 @@ struct AppGetNearbyChairsResponseChair {
 }
 
 async fn app_get_nearby_chairs(
-    State(AppState { pool, .. }): State<AppState>,
+    State(AppState { chair_registry, .. }): State<AppState>,
     Query(query): Query<AppGetNearbyChairsQuery>,
 ) -> Result<axum::Json<AppGetNearbyChairsResponse>, Error> {
     let distance = query.distance.unwrap_or(50);
@@ -832,126 +1256,45 @@ async fn app_get_nearby_chairs(
         longitude: query.longitude,
     };
 
-    let mut tx = pool.begin().await?;
-
-    let chairs: Vec<Chair> = sqlx::query_as("SELECT * FROM chairs")
-        .fetch_all(&mut *tx)
-        .await?;
-
-    let mut nearby_chairs = Vec::new();
-    for chair in chairs {
-        if !chair.is_active {
-            continue;
-        }
-
-        let rides: Vec<Ride> =
-            sqlx::query_as("SELECT * FROM rides WHERE chair_id = ? ORDER BY created_at DESC")
-                .bind(&chair.id)
-                .fetch_all(&mut *tx)
-                .await?;
-
-        let mut skip = false;
-        for ride in rides {
-            // 過去にライドが存在し、かつ、それが完了していない場合はスキップ
-            let status = crate::get_latest_ride_status(&mut *tx, &ride.id).await?;
-            if status != "COMPLETED" {
-                skip = true;
-                break;
-            }
-        }
-        if skip {
-            continue;
-        }
-
-        // 最新の位置情報を取得
-        let Some(chair_location): Option<ChairLocation> = sqlx::query_as(
-            "SELECT * FROM chair_locations WHERE chair_id = ? ORDER BY created_at DESC LIMIT 1",
-        )
-        .bind(&chair.id)
-        .fetch_optional(&mut *tx)
-        .await?
-        else {
-            continue;
-        };
-        if crate::calculate_distance(
-            coordinate.latitude,
-            coordinate.longitude,
-            chair_location.latitude,
-            chair_location.longitude,
-        ) <= distance
-        {
-            nearby_chairs.push(AppGetNearbyChairsResponseChair {
-                id: chair.id,
+    let nearby_chairs = chair_registry
+        .nearby_free_chairs(coordinate, distance, query.limit)
+        .into_iter()
+        .filter_map(|(chair_id, chair)| {
+            Some(AppGetNearbyChairsResponseChair {
+                id: chair_id,
                 name: chair.name,
                 model: chair.model,
-                current_coordinate: Coordinate {
-                    latitude: chair_location.latitude,
-                    longitude: chair_location.longitude,
-                },
-            });
-        }
-    }
-
-    let retrieved_at: chrono::DateTime<chrono::Utc> =
-        sqlx::query_scalar("SELECT CURRENT_TIMESTAMP(6)")
-            .fetch_one(&mut *tx)
-            .await?;
+                current_coordinate: chair.location?,
+            })
+        })
+        .collect();
 
     Ok(axum::Json(AppGetNearbyChairsResponse {
         chairs: nearby_chairs,
-        retrieved_at: retrieved_at.timestamp(),
+        retrieved_at: chrono::Utc::now().timestamp(),
     }))
 }
 
-async fn calculate_discounted_fare(
+/// ライドの運賃。運賃を固定する前に作ったライドは、以前と同じように今の料金と使ったクーポンから計算する
+pub(crate) async fn calculate_discounted_fare(
     tx: &mut sqlx::MySqlConnection,
-    user_id: &str,
-    ride: Option<&Ride>,
-    mut pickup_latitude: i32,
-    mut pickup_longitude: i32,
-    mut dest_latitude: i32,
-    mut dest_longitude: i32,
+    ride: &Ride,
 ) -> sqlx::Result<i32> {
-    let discount = if let Some(ride) = ride {
-        dest_latitude = ride.destination_latitude;
-        dest_longitude = ride.destination_longitude;
-        pickup_latitude = ride.pickup_latitude;
-        pickup_longitude = ride.pickup_longitude;
-
-        // すでにクーポンが紐づいているならそれの割引額を参照
-        let coupon: Option<Coupon> = sqlx::query_as("SELECT * FROM coupons WHERE used_by = ?")
-            .bind(&ride.id)
-            .fetch_optional(&mut *tx)
-            .await?;
-        coupon.map(|c| c.discount).unwrap_or(0)
-    } else {
-        // 初回利用クーポンを最優先で使う
-        let coupon: Option<Coupon> = sqlx::query_as(
-            "SELECT * FROM coupons WHERE user_id = ? AND code = 'CP_NEW2024' AND used_by IS NULL",
-        )
-        .bind(user_id)
+    if let Some(quoted_fare) = ride.quoted_fare {
+        return Ok(quoted_fare);
+    }
+
+    let pickup = ride.pickup_coordinate();
+    let destination = ride.destination_coordinate();
+    let pricing = PricingEngine::load(&mut *tx).await?;
+    let coupon: Option<Coupon> = sqlx::query_as("SELECT * FROM coupons WHERE used_by = ?")
+        .bind(&ride.id)
         .fetch_optional(&mut *tx)
         .await?;
-        if let Some(coupon) = coupon {
-            coupon.discount
-        } else {
-            // 無いなら他のクーポンを付与された順番に使う
-            let coupon: Option<Coupon> = sqlx::query_as("SELECT * FROM coupons WHERE user_id = ? AND used_by IS NULL ORDER BY created_at LIMIT 1")
-                .bind(user_id)
-                .fetch_optional(&mut *tx)
-                .await?;
-            coupon.map(|c| c.discount).unwrap_or(0)
-        }
-    };
-
-    let metered_fare = crate::FARE_PER_DISTANCE
-        * crate::calculate_distance(
-            pickup_latitude,
-            pickup_longitude,
-            dest_latitude,
-            dest_longitude,
-        );
-    let discounted_metered_fare = std::cmp::max(metered_fare - discount, 0);
+    let metered_fare = pricing.metered_fare(None, ride.surge_multiplier, pickup, destination);
+    let discount = coupon
+        .map(|coupon| coupon.discount_for(metered_fare))
+        .unwrap_or(0);
 
-    Ok(crate::INITIAL_FARE + discounted_metered_fare)
+    Ok(pricing.discounted_fare(None, ride.surge_multiplier, pickup, destination, discount))
 }
@@ -0,0 +1,265 @@
+//! 決済マイクロサービスのモック。
+//!
+//! `POST /payments`、`GET /payments` と `POST /refunds` だけを実装していて、`/api/initialize` の `payment_server` に
+//! このサーバーの URL を渡すとローカルで決済まわりを試せる。
+//! 以下の環境変数で障害を起こせる (確率は 0.0〜1.0)。
+//!
+//! - `MOCK_PAYMENT_GATEWAY_PORT`: 待ち受けるポート (デフォルト 12345)
+//! - `MOCK_PAYMENT_GATEWAY_LATENCY_MS`: 各リクエストに 0〜指定ミリ秒の遅延を入れる
+//! - `MOCK_PAYMENT_GATEWAY_ERROR_RATE`: 決済せずに 500 を返す確率
+//! - `MOCK_PAYMENT_GATEWAY_DROP_RATE`: 決済した後にレスポンスを返さず接続を切る確率
+//! - `MOCK_PAYMENT_GATEWAY_DUPLICATE_RATE`: Idempotency-Key を無視して二重に決済する確率
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse as _, Response};
+use dashmap::DashMap;
+use rand::Rng as _;
+use tokio::net::TcpListener;
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone, Copy)]
+struct FaultConfig {
+    max_latency: Duration,
+    error_rate: f64,
+    drop_rate: f64,
+    duplicate_rate: f64,
+}
+impl FaultConfig {
+    fn from_env() -> Self {
+        Self {
+            max_latency: Duration::from_millis(env_or("MOCK_PAYMENT_GATEWAY_LATENCY_MS", 0)),
+            error_rate: env_or("MOCK_PAYMENT_GATEWAY_ERROR_RATE", 0.0),
+            drop_rate: env_or("MOCK_PAYMENT_GATEWAY_DROP_RATE", 0.0),
+            duplicate_rate: env_or("MOCK_PAYMENT_GATEWAY_DUPLICATE_RATE", 0.0),
+        }
+    }
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("failed to parse {name} environment variable: {value}")),
+        Err(_) => default,
+    }
+}
+
+fn happens(rate: f64) -> bool {
+    rate > 0.0 && rand::thread_rng().gen::<f64>() < rate
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct PaymentRecord {
+    amount: i32,
+    status: &'static str,
+}
+
+#[derive(Debug, Clone)]
+struct MockState {
+    faults: FaultConfig,
+    /// トークンごとの決済の記録
+    payments: Arc<DashMap<String, Vec<PaymentRecord>>>,
+    /// 処理済みの `(トークン, Idempotency-Key)`
+    processed_keys: Arc<Mutex<HashSet<(String, String)>>>,
+    /// 決済の Idempotency-Key ごとの返金済みの額
+    refunded: Arc<DashMap<(String, String), i32>>,
+}
+
+/// ハンドラーから接続を切るよう接続のタスクに伝える
+#[derive(Debug, Clone)]
+struct DropConnection(Arc<Notify>);
+
+#[derive(Debug, serde::Deserialize)]
+struct PostPaymentRequest {
+    amount: i32,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PostRefundRequest {
+    payment_idempotency_key: String,
+    amount: i32,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ErrorBody {
+    message: &'static str,
+}
+
+fn error_response(status: StatusCode, message: &'static str) -> Response {
+    (status, axum::Json(ErrorBody { message })).into_response()
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get("Authorization")?.to_str().ok()?;
+    let token = value.strip_prefix("Bearer ")?;
+    (!token.is_empty()).then(|| token.to_owned())
+}
+
+async fn inject_latency(faults: &FaultConfig) {
+    if faults.max_latency.is_zero() {
+        return;
+    }
+    let latency = faults.max_latency.mul_f64(rand::thread_rng().gen::<f64>());
+    tokio::time::sleep(latency).await;
+}
+
+async fn post_payments(
+    State(state): State<MockState>,
+    axum::Extension(drop_connection): axum::Extension<DropConnection>,
+    headers: HeaderMap,
+    axum::Json(req): axum::Json<PostPaymentRequest>,
+) -> Response {
+    inject_latency(&state.faults).await;
+
+    let Some(token) = bearer_token(&headers) else {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid token");
+    };
+    if req.amount <= 0 {
+        return error_response(StatusCode::BAD_REQUEST, "invalid amount");
+    }
+    if happens(state.faults.error_rate) {
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, "injected error");
+    }
+
+    let idempotency_key = headers
+        .get("Idempotency-Key")
+        .and_then(|value| value.to_str().ok())
+        .map(ToOwned::to_owned);
+    let first_time = match idempotency_key {
+        Some(key) => state
+            .processed_keys
+            .lock()
+            .unwrap()
+            .insert((token.clone(), key)),
+        None => true,
+    };
+    if first_time || happens(state.faults.duplicate_rate) {
+        state
+            .payments
+            .entry(token)
+            .or_default()
+            .push(PaymentRecord {
+                amount: req.amount,
+                status: "成功",
+            });
+    }
+
+    // 決済は済ませたがクライアントには結果が届かない状況を作る
+    if happens(state.faults.drop_rate) {
+        drop_connection.0.notify_one();
+        std::future::pending::<()>().await;
+    }
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn post_refunds(
+    State(state): State<MockState>,
+    headers: HeaderMap,
+    axum::Json(req): axum::Json<PostRefundRequest>,
+) -> Response {
+    inject_latency(&state.faults).await;
+
+    let Some(token) = bearer_token(&headers) else {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid token");
+    };
+    if req.amount <= 0 {
+        return error_response(StatusCode::BAD_REQUEST, "invalid amount");
+    }
+    if happens(state.faults.error_rate) {
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, "injected error");
+    }
+
+    let payment_key = (token, req.payment_idempotency_key);
+    if !state.processed_keys.lock().unwrap().contains(&payment_key) {
+        return error_response(StatusCode::NOT_FOUND, "payment not found");
+    }
+    let idempotency_key = headers
+        .get("Idempotency-Key")
+        .and_then(|value| value.to_str().ok())
+        .map(|key| (payment_key.0.clone(), format!("refund:{key}")));
+    let first_time = match idempotency_key {
+        Some(key) => state.processed_keys.lock().unwrap().insert(key),
+        None => true,
+    };
+    if first_time {
+        *state.refunded.entry(payment_key).or_default() += req.amount;
+    }
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn get_payments(State(state): State<MockState>, headers: HeaderMap) -> Response {
+    inject_latency(&state.faults).await;
+
+    let Some(token) = bearer_token(&headers) else {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid token");
+    };
+    if happens(state.faults.error_rate) {
+        return error_response(StatusCode::INTERNAL_SERVER_ERROR, "injected error");
+    }
+
+    let payments = state
+        .payments
+        .get(&token)
+        .map(|payments| payments.clone())
+        .unwrap_or_default();
+    axum::Json(payments).into_response()
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    tracing_subscriber::fmt::init();
+
+    let port: u16 = env_or("MOCK_PAYMENT_GATEWAY_PORT", 12345);
+    let faults = FaultConfig::from_env();
+    tracing::info!(port, ?faults, "starting mock payment gateway");
+
+    let state = MockState {
+        faults,
+        payments: Arc::new(DashMap::new()),
+        processed_keys: Arc::new(Mutex::new(HashSet::new())),
+        refunded: Arc::new(DashMap::new()),
+    };
+    let app = axum::Router::new()
+        .route(
+            "/payments",
+            axum::routing::post(post_payments).get(get_payments),
+        )
+        .route("/refunds", axum::routing::post(post_refunds))
+        .with_state(state)
+        .layer(tower_http::trace::TraceLayer::new_for_http());
+
+    let tcp_listener = TcpListener::bind(&SocketAddr::from(([0, 0, 0, 0], port))).await?;
+    // 接続を途中で切れるように、axum::serve を使わずに接続ごとに hyper で処理する
+    loop {
+        let (stream, _) = tcp_listener.accept().await?;
+        let drop_connection = DropConnection(Arc::new(Notify::new()));
+        let service = hyper_util::service::TowerToHyperService::new(
+            app.clone().layer(axum::Extension(drop_connection.clone())),
+        );
+        tokio::spawn(async move {
+            let conn = hyper::server::conn::http1::Builder::new()
+                .serve_connection(hyper_util::rt::TokioIo::new(stream), service);
+            tokio::select! {
+                result = conn => {
+                    if let Err(e) = result {
+                        tracing::debug!(e = e.to_string(), "connection error");
+                    }
+                }
+                _ = drop_connection.0.notified() => {
+                    tracing::info!("dropped connection");
+                }
+            }
+        });
+    }
+}
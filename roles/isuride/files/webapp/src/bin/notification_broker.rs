@@ -0,0 +1,87 @@
+//! 複数の isuride のインスタンスの間で通知のイベントを中継するブローカー。
+//!
+//! 各インスタンスは `ISUCON_NOTIFICATION_BROKER` にこのサーバーのアドレスを渡して接続する。
+//! 接続から受け取った 1 行を、送り主以外のすべての接続にそのまま送る。中身は解釈しない。
+//!
+//! - `NOTIFICATION_BROKER_PORT`: 待ち受けるポート (デフォルト 5555)
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+/// 遅い接続のために溜めておく行の数。溢れた分はその接続には届かない
+const CHANNEL_CAPACITY: usize = 4096;
+
+/// (送り主の接続の番号, 改行を含まない 1 行)
+type Frame = (u64, Arc<str>);
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    tracing_subscriber::fmt::init();
+
+    let port: u16 = match std::env::var("NOTIFICATION_BROKER_PORT") {
+        Ok(port) => port.parse().unwrap_or_else(|_| {
+            panic!("failed to parse NOTIFICATION_BROKER_PORT environment variable: {port}")
+        }),
+        Err(_) => 5555,
+    };
+    tracing::info!(port, "starting notification broker");
+
+    let (tx, _) = broadcast::channel::<Frame>(CHANNEL_CAPACITY);
+    let tcp_listener = TcpListener::bind(&SocketAddr::from(([0, 0, 0, 0], port))).await?;
+    let mut next_id = 0;
+    loop {
+        let (stream, peer) = tcp_listener.accept().await?;
+        let id = next_id;
+        next_id += 1;
+        tracing::info!(id, %peer, "instance connected");
+        let tx = tx.clone();
+        let rx = tx.subscribe();
+        tokio::spawn(async move {
+            if let Err(e) = relay(stream, id, tx, rx).await {
+                tracing::warn!(id, e = e.to_string(), "connection error");
+            }
+            tracing::info!(id, "instance disconnected");
+        });
+    }
+}
+
+async fn relay(
+    stream: TcpStream,
+    id: u64,
+    tx: broadcast::Sender<Frame>,
+    mut rx: broadcast::Receiver<Frame>,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Some(line) = line? else {
+                    return Ok(());
+                };
+                // 他に誰も接続していなければ送り先が無いだけなので無視する
+                let _ = tx.send((id, line.into()));
+            }
+            frame = rx.recv() => match frame {
+                Ok((from, line)) => {
+                    if from == id {
+                        continue;
+                    }
+                    writer.write_all(line.as_bytes()).await?;
+                    writer.write_all(b"\n").await?;
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!(id, skipped, "instance lagged, dropping events");
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
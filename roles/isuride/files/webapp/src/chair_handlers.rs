@@ -1,22 +1,25 @@
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
 
 use async_stream::stream;
 use axum::extract::{Path, State};
-use axum::http::StatusCode;
+use axum::http::{HeaderMap, StatusCode};
 use axum::response::sse::Event;
 use axum::response::Sse;
 use axum_extra::extract::cookie::Cookie;
 use axum_extra::extract::CookieJar;
-use dashmap::DashMap;
 use futures::Stream;
 use sqlx::MySqlPool;
-use tokio::sync::watch;
+use tokio::sync::{broadcast, Notify};
 use tokio_stream::StreamExt as _;
 use tracing::{info, warn};
 use ulid::Ulid;
 
-use crate::models::{Chair, ChairLocation, Owner, Ride, RideStatus, User};
+use crate::app_handlers::PostNotificationAckRequest;
+use crate::chair_registry::ChairRegistry;
+use crate::events::{Audience, EventBus, RideEvent, RideSnapshot};
+use crate::models::{Chair, ChairLocation, MatchingEvent, Owner, Ride, RideState};
 use crate::{AppState, Coordinate, Error};
 
 pub fn chair_routes(app_state: AppState) -> axum::Router<AppState> {
@@ -36,10 +39,18 @@ pub fn chair_routes(app_state: AppState) -> axum::Router<AppState> {
             "/api/chair/notification",
             axum::routing::get(chair_get_notification),
         )
+        .route(
+            "/api/chair/notification/ack",
+            axum::routing::post(chair_post_notification_ack),
+        )
         .route(
             "/api/chair/rides/:ride_id/status",
             axum::routing::post(chair_post_ride_status),
         )
+        .route(
+            "/api/chair/ws",
+            axum::routing::get(crate::chair_ws::chair_get_ws),
+        )
         .route_layer(axum::middleware::from_fn_with_state(
             app_state.clone(),
             crate::middlewares::chair_auth_middleware,
@@ -62,7 +73,11 @@ struct ChairPostChairsResponse {
 }
 
 async fn chair_post_chairs(
-    State(AppState { pool, .. }): State<AppState>,
+    State(AppState {
+        pool,
+        chair_registry,
+        ..
+    }): State<AppState>,
     jar: CookieJar,
     axum::Json(req): axum::Json<ChairPostChairsRequest>,
 ) -> Result<(CookieJar, (StatusCode, axum::Json<ChairPostChairsResponse>)), Error> {
@@ -81,13 +96,22 @@ async fn chair_post_chairs(
     sqlx::query("INSERT INTO chairs (id, owner_id, name, model, is_active, access_token) VALUES (?, ?, ?, ?, ?, ?)")
         .bind(&chair_id)
         .bind(&owner.id)
-        .bind(req.name)
-        .bind(req.model)
+        .bind(&req.name)
+        .bind(&req.model)
         .bind(false)
         .bind(&access_token)
         .execute(&pool)
         .await?;
 
+    // chair_models に無いモデルの椅子はマッチングの対象にしない
+    let speed: Option<i32> = sqlx::query_scalar("SELECT speed FROM chair_models WHERE name = ?")
+        .bind(&req.model)
+        .fetch_optional(&pool)
+        .await?;
+    if let Some(speed) = speed {
+        chair_registry.register(chair_id.clone(), req.name, req.model, speed);
+    }
+
     let jar = jar.add(Cookie::build(("chair_session", access_token)).path("/"));
 
     Ok((
@@ -108,33 +132,55 @@ struct PostChairActivityRequest {
 }
 
 async fn chair_post_activity(
-    State(AppState { pool, .. }): State<AppState>,
+    State(AppState {
+        pool,
+        matching_notify,
+        chair_registry,
+        ..
+    }): State<AppState>,
     axum::Extension(chair): axum::Extension<Chair>,
     axum::Json(req): axum::Json<PostChairActivityRequest>,
 ) -> Result<StatusCode, Error> {
     sqlx::query("UPDATE chairs SET is_active = ? WHERE id = ?")
         .bind(req.is_active)
-        .bind(chair.id)
+        .bind(&chair.id)
         .execute(&pool)
         .await?;
 
+    chair_registry.set_active(&chair.id, req.is_active);
+
+    if req.is_active {
+        matching_notify.notify_one();
+    }
+
     Ok(StatusCode::NO_CONTENT)
 }
 
 #[derive(Debug, serde::Serialize)]
-struct ChairPostCoordinateResponse {
-    recorded_at: i64,
+pub(crate) struct ChairPostCoordinateResponse {
+    pub recorded_at: i64,
 }
 
 async fn chair_post_coordinate(
-    State(AppState {
-        pool,
-        ride_status_notify_by_chair_id,
-        ride_status_notify_by_user_id,
-    }): State<AppState>,
+    State(state): State<AppState>,
     axum::Extension(chair): axum::Extension<Chair>,
     axum::Json(req): axum::Json<Coordinate>,
 ) -> Result<axum::Json<ChairPostCoordinateResponse>, Error> {
+    record_coordinate(&state, &chair, req).await.map(axum::Json)
+}
+
+/// 椅子の位置を記録し、乗車位置や目的地に着いていればライドの状態を進める。
+/// HTTP と WebSocket の両方から使う
+pub(crate) async fn record_coordinate(
+    AppState {
+        pool,
+        event_bus,
+        chair_registry,
+        ..
+    }: &AppState,
+    chair: &Chair,
+    req: Coordinate,
+) -> Result<ChairPostCoordinateResponse, Error> {
     let chair_location_id = Ulid::new().to_string();
 
     let mut tx = pool.begin().await?;
@@ -172,9 +218,11 @@ async fn chair_post_coordinate(
 
     tx.commit().await?;
 
+    chair_registry.update_location(&chair.id, req);
+
     let location: ChairLocation = sqlx::query_as("SELECT * FROM chair_locations WHERE id = ?")
         .bind(chair_location_id)
-        .fetch_one(&pool)
+        .fetch_one(pool)
         .await?;
 
     let mut tx = pool.begin().await?;
@@ -184,58 +232,57 @@ async fn chair_post_coordinate(
             .bind(chair.id.clone())
             .fetch_optional(&mut *tx)
             .await?;
+    let mut user_id = None;
+    let mut status_changed = None;
     if let Some(ride) = &ride {
         let status = crate::get_latest_ride_status(&mut *tx, &ride.id).await?;
-        if status != "COMPLETED" && status != "CANCELED" {
-            if req.latitude == ride.pickup_latitude
-                && req.longitude == ride.pickup_longitude
-                && status == "ENROUTE"
-            {
-                sqlx::query("INSERT INTO ride_statuses (id, ride_id, status) VALUES (?, ?, ?)")
-                    .bind(Ulid::new().to_string())
-                    .bind(&ride.id)
-                    .bind("PICKUP")
-                    .execute(&mut *tx)
-                    .await?;
-            }
-
-            if req.latitude == ride.destination_latitude
-                && req.longitude == ride.destination_longitude
-                && status == "CARRYING"
-            {
-                sqlx::query("INSERT INTO ride_statuses (id, ride_id, status) VALUES (?, ?, ?)")
-                    .bind(Ulid::new().to_string())
-                    .bind(&ride.id)
-                    .bind("ARRIVED")
-                    .execute(&mut *tx)
-                    .await?;
+        if !status.is_finished() {
+            user_id = Some(ride.user_id.clone());
+
+            // 迎車中に乗車位置、乗車中に目的地に着いたら状態を進める。
+            // 今の状態から進められるかは transition_ride_status が判定する
+            let arrivals = [
+                (ride.pickup_coordinate(), RideState::Pickup),
+                (ride.destination_coordinate(), RideState::Arrived),
+            ];
+            for (coordinate, next) in arrivals {
+                if req != coordinate {
+                    continue;
+                }
+                match crate::transition_ride_status(&mut tx, &ride.id, next).await {
+                    Ok(transition) => {
+                        status_changed = Some((transition, next));
+                        break;
+                    }
+                    Err(Error::InvalidRideStateTransition { .. }) => {}
+                    Err(e) => return Err(e),
+                }
             }
         }
     }
 
     tx.commit().await?;
 
-    ride_status_notify_by_chair_id
-        .entry(chair.id.clone())
-        .or_insert_with(|| watch::channel(Ulid::new()))
-        .0
-        .send(Ulid::new())
-        .unwrap();
-    info!(chair_id = chair.id, "notify chair change");
-
-    if let Some(ride) = &ride {
-        ride_status_notify_by_user_id
-            .entry(ride.user_id.clone())
-            .or_insert_with(|| watch::channel(Ulid::new()))
-            .0
-            .send(Ulid::new())
-            .unwrap();
-        info!(user_id = ride.user_id, "notify user change");
+    event_bus.publish(RideEvent::LocationUpdated {
+        chair_id: chair.id.clone(),
+        user_id,
+        location: req,
+    });
+    if let (Some(ride), Some((transition, status))) = (&ride, status_changed) {
+        event_bus
+            .publish_status_changed(
+                pool,
+                &ride.id,
+                transition.ride_status_id,
+                Some(transition.previous_ride_status_id),
+                status,
+            )
+            .await;
     }
 
-    Ok(axum::Json(ChairPostCoordinateResponse {
+    Ok(ChairPostCoordinateResponse {
         recorded_at: location.created_at.timestamp_millis(),
-    }))
+    })
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -245,124 +292,220 @@ struct SimpleUser {
 }
 
 #[derive(Debug, serde::Serialize)]
-struct ChairGetNotificationResponseData {
+pub(crate) struct ChairGetNotificationResponseData {
     ride_id: String,
     user: SimpleUser,
     pickup_coordinate: Coordinate,
     destination_coordinate: Coordinate,
-    status: String,
+    status: RideState,
+    /// 割り当てが外れたライド。椅子はこのライドに向かわずに次の割り当てを待つ
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    unassigned: bool,
+}
+
+fn chair_notification_data(
+    ride: &RideSnapshot,
+    status: RideState,
+) -> ChairGetNotificationResponseData {
+    ChairGetNotificationResponseData {
+        ride_id: ride.ride_id.clone(),
+        user: SimpleUser {
+            id: ride.user_id.clone(),
+            name: ride.user_name.clone(),
+        },
+        pickup_coordinate: ride.pickup,
+        destination_coordinate: ride.destination,
+        status,
+        unassigned: false,
+    }
 }
 
 fn chair_notification_stream(
-    mut chair_notification: watch::Receiver<Ulid>,
-    user_notify: Arc<DashMap<String, (watch::Sender<Ulid>, watch::Receiver<Ulid>)>>,
+    mut events: broadcast::Receiver<RideEvent>,
+    event_bus: Arc<EventBus>,
+    matching_notify: Arc<Notify>,
+    chair_registry: Arc<ChairRegistry>,
     pool: MySqlPool,
     chair_id: String,
-) -> impl Stream<Item = Result<Option<ChairGetNotificationResponseData>, Error>> {
+    last_event_id: Option<String>,
+) -> impl Stream<Item = Result<Option<(String, ChairGetNotificationResponseData)>, Error>> {
     info!(chair_id, "open new notification channel for chair");
     stream! {
-        loop {
-            let mut tx = pool.begin().await?;
-
-            let Some(ride): Option<Ride> =
-                sqlx::query_as("SELECT * FROM rides WHERE chair_id = ? ORDER BY updated_at DESC LIMIT 1")
-                    .bind(&chair_id)
-                    .fetch_optional(&mut *tx)
-                    .await?
-            else {
-                tx.commit().await?;
-                info!(chair_id, "no ride found");
-                yield Ok(None);
-                chair_notification.changed().await.unwrap();
-                info!(chair_id, "got new ride");
-                continue;
-            };
-
-            let yet_sent_ride_status: Option<RideStatus> =
-                sqlx::query_as("SELECT * FROM ride_statuses WHERE ride_id = ? AND chair_sent_at IS NULL ORDER BY created_at ASC LIMIT 1")
-                .bind(&ride.id)
-                .fetch_optional(&mut *tx)
-                .await?;
-            let (yet_sent_ride_status_id, status) = if let Some(yet_sent_ride_status) = yet_sent_ride_status
-            {
-                (Some(yet_sent_ride_status.id), yet_sent_ride_status.status)
-            } else {
-                (None, crate::get_latest_ride_status(&mut *tx, &ride.id).await?,)
-            };
-
-            let user: User = sqlx::query_as("SELECT * FROM users WHERE id = ? FOR SHARE")
-                .bind(ride.user_id.clone())
-                .fetch_one(&mut *tx)
-                .await?;
-
-            if let Some(yet_sent_ride_status_id) = yet_sent_ride_status_id {
-                sqlx::query("UPDATE ride_statuses SET chair_sent_at = CURRENT_TIMESTAMP(6) WHERE id = ?")
-                    .bind(yet_sent_ride_status_id)
-                    .execute(&mut *tx)
+        // 最後に送った (ライドID, 状態ID)
+        let mut last_sent: Option<(String, String)> = None;
+        let mut sent_ride_status_ids = HashSet::new();
+        let mut resume_after = last_event_id;
+
+        // 購読を始めたときと、イベントを取りこぼしたときだけ DB から読み直す
+        'reload: loop {
+            let ride_id: Option<String> = sqlx::query_scalar(
+                "SELECT id FROM rides WHERE chair_id = ? ORDER BY updated_at DESC LIMIT 1",
+            )
+            .bind(&chair_id)
+            .fetch_optional(&pool)
+            .await?;
+            match ride_id {
+                None if last_sent.is_none() => {
+                    info!(chair_id, "no ride found");
+                    yield Ok(None);
+                }
+                None => {}
+                Some(ride_id) => {
+                    let after = resume_after.take().or_else(|| {
+                        last_sent
+                            .as_ref()
+                            .filter(|(sent_ride_id, _)| *sent_ride_id == ride_id)
+                            .map(|(_, ride_status_id)| ride_status_id.clone())
+                    });
+                    let mut pending = event_bus.load_pending_ride_statuses(
+                        &pool,
+                        Audience::Chair,
+                        &ride_id,
+                        after.as_deref(),
+                    )
                     .await?;
-
-                user_notify
-                    .entry(ride.user_id.clone())
-                    .or_insert_with(|| watch::channel(Ulid::new()))
-                    .0
-                    .send(Ulid::new())
-                    .unwrap();
+                    // 接続した直後は、送るものが無くても今の状態を送る
+                    if pending.pending.is_empty() && last_sent.is_none() {
+                        pending.pending.extend(pending.latest);
+                    }
+                    for ride_status in pending.pending {
+                        if !sent_ride_status_ids.insert(ride_status.id.clone()) {
+                            continue;
+                        }
+                        // COMPLETED か CANCELED を通知し終えた椅子は次のマッチング対象になる
+                        if ride_status.status.is_finished() {
+                            chair_registry.release(&chair_id, &ride_id);
+                            matching_notify.notify_one();
+                        }
+                        let data = chair_notification_data(&pending.ride, ride_status.status);
+                        last_sent = Some((ride_id.clone(), ride_status.id.clone()));
+                        info!(chair_id, status = data.status.as_str(), "send sse");
+                        yield Ok(Some((ride_status.id, data)));
+                    }
+                }
             }
 
-            tx.commit().await?;
-            info!(chair_id, status, "send sse");
-
-            yield Ok(Some(ChairGetNotificationResponseData {
-                ride_id: ride.id,
-                user: SimpleUser {
-                    id: user.id,
-                    name: format!("{} {}", user.firstname, user.lastname),
-                },
-                pickup_coordinate: Coordinate {
-                    latitude: ride.pickup_latitude,
-                    longitude: ride.pickup_longitude,
-                },
-                destination_coordinate: Coordinate {
-                    latitude: ride.destination_latitude,
-                    longitude: ride.destination_longitude,
-                },
-                status,
-            }));
-            chair_notification.changed().await.unwrap();
+            loop {
+                let (ride_status_id, data) = match events.recv().await {
+                    Ok(RideEvent::StatusChanged { ride_status_id, previous_ride_status_id, status, ride }) => {
+                        if sent_ride_status_ids.contains(&ride_status_id) {
+                            continue;
+                        }
+                        // 直前の状態を送っていなければ、順番を守るために DB から読み直す
+                        if let Some((sent_ride_id, sent_ride_status_id)) = &last_sent {
+                            if *sent_ride_id == ride.ride_id
+                                && previous_ride_status_id.as_ref() != Some(sent_ride_status_id)
+                            {
+                                warn!(chair_id, ride_status_id, "chair notification out of order");
+                                continue 'reload;
+                            }
+                        }
+                        sent_ride_status_ids.insert(ride_status_id.clone());
+                        if status.is_finished() {
+                            chair_registry.release(&chair_id, &ride.ride_id);
+                            matching_notify.notify_one();
+                        }
+                        (ride_status_id, chair_notification_data(&ride, status))
+                    }
+                    Ok(RideEvent::RideAssigned { ride_status_id, status, ride, previous_chair_id }) => {
+                        if ride.chair_id() == Some(chair_id.as_str()) {
+                            (ride_status_id, chair_notification_data(&ride, status))
+                        } else if previous_chair_id.as_deref() == Some(chair_id.as_str()) {
+                            info!(chair_id, ride_id = ride.ride_id, "ride unassigned from chair");
+                            let data = ChairGetNotificationResponseData {
+                                unassigned: true,
+                                ..chair_notification_data(&ride, status)
+                            };
+                            (ride_status_id, data)
+                        } else {
+                            continue;
+                        }
+                    }
+                    Ok(RideEvent::LocationUpdated { .. }) => continue,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!(chair_id, skipped, "chair notification lagged");
+                        continue 'reload;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                last_sent = Some((data.ride_id.clone(), ride_status_id.clone()));
+                info!(chair_id, status = data.status.as_str(), "send sse");
+                yield Ok(Some((ride_status_id, data)));
+            }
         }
     }
 }
 
-async fn chair_get_notification(
-    State(AppState {
+/// `last_event_id` までを受け取り済みにしてから、椅子への通知の購読を始める。
+/// SSE と WebSocket の両方から使う
+pub(crate) async fn subscribe_chair_notifications(
+    AppState {
         pool,
-        ride_status_notify_by_chair_id,
-        ride_status_notify_by_user_id,
+        event_bus,
+        matching_notify,
+        chair_registry,
         ..
-    }): State<AppState>,
+    }: &AppState,
+    chair_id: &str,
+    last_event_id: Option<String>,
+) -> Result<
+    impl Stream<Item = Result<Option<(String, ChairGetNotificationResponseData)>, Error>>,
+    Error,
+> {
+    // DB から読み直す前に購読しておき、その間に起きたイベントを取りこぼさないようにする
+    let events = event_bus.subscribe_chair(chair_id);
+
+    if let Some(last_event_id) = &last_event_id {
+        crate::events::acknowledge_ride_statuses(pool, Audience::Chair, chair_id, last_event_id)
+            .await?;
+    }
+
+    Ok(chair_notification_stream(
+        events,
+        event_bus.clone(),
+        matching_notify.clone(),
+        chair_registry.clone(),
+        pool.clone(),
+        chair_id.to_owned(),
+        last_event_id,
+    ))
+}
+
+/// SSE のイベントの ID は ride_statuses の ID。
+/// 再接続時の `Last-Event-ID` までを受け取り済みとみなし、その後の状態から順に送り直す
+async fn chair_get_notification(
+    State(state): State<AppState>,
     axum::Extension(chair): axum::Extension<Chair>,
-) -> Sse<impl Stream<Item = Result<Event, Error>>> {
-    let chair_notification = ride_status_notify_by_chair_id
-        .entry(chair.id.clone())
-        .or_insert_with(|| watch::channel(Ulid::new()))
-        .1
-        .clone();
-
-    let stream = chair_notification_stream(
-        chair_notification,
-        ride_status_notify_by_user_id,
-        pool,
-        chair.id.clone(),
-    );
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<Event, Error>>>, Error> {
+    let last_event_id = crate::app_handlers::last_event_id(&headers);
+    let stream = subscribe_chair_notifications(&state, &chair.id, last_event_id).await?;
     let stream = stream.map(|result| match result {
-        Ok(data) => Ok(Event::default().json_data(&data).unwrap()),
+        Ok(Some((ride_status_id, data))) => Ok(Event::default()
+            .id(ride_status_id)
+            .json_data(&data)
+            .unwrap()),
+        Ok(None) => Ok(Event::default().json_data(None::<()>).unwrap()),
         Err(e) => {
             warn!(e = e.to_string(), "error happend");
             Err(e)
         }
     });
 
-    Sse::new(stream.throttle(Duration::from_millis(300)))
+    Ok(Sse::new(stream.throttle(Duration::from_millis(300))))
+}
+
+/// 通知を受け取ったことを確認し、それより前の状態も送信済みにする
+async fn chair_post_notification_ack(
+    State(AppState { pool, .. }): State<AppState>,
+    axum::Extension(chair): axum::Extension<Chair>,
+    axum::Json(req): axum::Json<PostNotificationAckRequest>,
+) -> Result<StatusCode, Error> {
+    if !crate::events::acknowledge_ride_statuses(&pool, Audience::Chair, &chair.id, &req.id).await?
+    {
+        return Err(Error::NotFound("notification not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
 }
 
 #[derive(Debug, serde::Deserialize)]
@@ -371,15 +514,52 @@ struct PostChairRidesRideIDStatusRequest {
 }
 
 async fn chair_post_ride_status(
-    State(AppState {
-        pool,
-        ride_status_notify_by_chair_id,
-        ride_status_notify_by_user_id,
-    }): State<AppState>,
+    State(state): State<AppState>,
     axum::Extension(chair): axum::Extension<Chair>,
     Path((ride_id,)): Path<(String,)>,
     axum::Json(req): axum::Json<PostChairRidesRideIDStatusRequest>,
 ) -> Result<StatusCode, Error> {
+    update_ride_status(&state, &chair, ride_id, req.status.parse()?).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// 椅子が `POST /api/chair/rides/{ride_id}/status` などで送ってくるライドの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChairRideStatus {
+    /// ライドを受理する
+    Enroute,
+    /// ライドを拒否する
+    Rejected,
+    /// 乗車した
+    Carrying,
+}
+impl std::str::FromStr for ChairRideStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ENROUTE" => Ok(Self::Enroute),
+            "REJECTED" => Ok(Self::Rejected),
+            "CARRYING" => Ok(Self::Carrying),
+            _ => Err(Error::BadRequest("invalid status")),
+        }
+    }
+}
+
+/// 椅子がライドを受理 (ENROUTE)、拒否 (REJECTED) したり、乗車 (CARRYING) を記録する。
+/// HTTP と WebSocket の両方から使う
+pub(crate) async fn update_ride_status(
+    AppState {
+        pool,
+        event_bus,
+        matching_notify,
+        chair_registry,
+        ..
+    }: &AppState,
+    chair: &Chair,
+    ride_id: String,
+    status: ChairRideStatus,
+) -> Result<(), Error> {
     let mut tx = pool.begin().await?;
 
     let Some(ride): Option<Ride> = sqlx::query_as("SELECT * FROM rides WHERE id = ? FOR UPDATE")
@@ -394,51 +574,61 @@ async fn chair_post_ride_status(
         return Err(Error::BadRequest("not assigned to this ride"));
     }
 
-    match req.status.as_str() {
+    let status_changed = match status {
         // Acknowledge the ride
-        "ENROUTE" => {
-            sqlx::query("INSERT INTO ride_statuses (id, ride_id, status) VALUES (?, ?, ?)")
-                .bind(Ulid::new().to_string())
-                .bind(ride.id)
-                .bind("ENROUTE")
-                .execute(&mut *tx)
-                .await?;
+        ChairRideStatus::Enroute => {
+            let transition =
+                crate::transition_ride_status(&mut tx, &ride.id, RideState::Enroute).await?;
+            Some((transition, RideState::Enroute))
         }
-        // After Picking up user
-        "CARRYING" => {
-            let status = crate::get_latest_ride_status(&mut *tx, &ride.id).await?;
-            if status != "PICKUP" {
-                return Err(Error::BadRequest("chair has not arrived yet"));
+        // Reject the ride
+        ChairRideStatus::Rejected => {
+            let unassigned = crate::internal_handlers::unassign_ride(
+                &mut tx,
+                &ride.id,
+                &chair.id,
+                MatchingEvent::Rejected,
+            )
+            .await?;
+            if !unassigned {
+                return Err(Error::BadRequest("ride has already been accepted"));
             }
-            sqlx::query("INSERT INTO ride_statuses (id, ride_id, status) VALUES (?, ?, ?)")
-                .bind(Ulid::new().to_string())
-                .bind(ride.id)
-                .bind("CARRYING")
-                .execute(&mut *tx)
-                .await?;
+            None
         }
-        _ => {
-            return Err(Error::BadRequest("invalid status"));
+        // After Picking up user
+        ChairRideStatus::Carrying => {
+            let transition =
+                crate::transition_ride_status(&mut tx, &ride.id, RideState::Carrying).await?;
+            Some((transition, RideState::Carrying))
         }
     };
 
     tx.commit().await?;
 
-    ride_status_notify_by_chair_id
-        .entry(chair.id.clone())
-        .or_insert_with(|| watch::channel(Ulid::new()))
-        .0
-        .send(Ulid::new())
-        .unwrap();
-    info!(chair_id = chair.id, "notify chair change");
-
-    ride_status_notify_by_user_id
-        .entry(ride.user_id.clone())
-        .or_insert_with(|| watch::channel(Ulid::new()))
-        .0
-        .send(Ulid::new())
-        .unwrap();
-    info!(user_id = ride.user_id, "notify chair change");
+    match status_changed {
+        Some((transition, status)) => {
+            // コミットしてから反映する。ロールバックされたら受理していない扱いのままにする
+            if status == RideState::Enroute {
+                chair_registry.acknowledge(&chair.id, &ride.id);
+            }
+            event_bus
+                .publish_status_changed(
+                    pool,
+                    &ride.id,
+                    transition.ride_status_id,
+                    Some(transition.previous_ride_status_id),
+                    status,
+                )
+                .await;
+        }
+        None => {
+            chair_registry.release(&chair.id, &ride.id);
+            event_bus
+                .publish_ride_assigned(pool, &ride.id, Some(&chair.id))
+                .await;
+            matching_notify.notify_one();
+        }
+    }
 
-    Ok(StatusCode::NO_CONTENT)
+    Ok(())
 }
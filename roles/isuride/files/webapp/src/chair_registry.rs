@@ -0,0 +1,502 @@
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use tracing::warn;
+
+use crate::matching::FreeChair;
+use crate::notification_backend::{InMemoryBackend, NotificationBackend, RelayMessage};
+use crate::spatial_index::GridIndex;
+use crate::Coordinate;
+
+/// 近くの椅子の検索に使うグリッドのセルの大きさ
+const GRID_CELL_SIZE: i32 = 32;
+/// 続けてこの回数だけ割り当てに応答しなかった椅子は、位置の更新などで応答するまでマッチングの対象から外す
+const MAX_CONSECUTIVE_TIMEOUTS: u32 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChairState {
+    Inactive,
+    Free,
+    /// 稼働中だが割り当てに応答しない (通信が切れているとみなす)
+    Unresponsive,
+    /// 割り当てられたライドの ID
+    Assigned(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ChairEntry {
+    pub name: String,
+    pub model: String,
+    pub speed: i32,
+    pub is_active: bool,
+    pub ride_id: Option<String>,
+    /// 割り当てられたライドを椅子が受理 (ENROUTE) したかどうか
+    pub acknowledged: bool,
+    pub assigned_at: Option<Instant>,
+    pub location: Option<Coordinate>,
+    /// 続けて割り当てに応答しなかった回数
+    pub consecutive_timeouts: u32,
+}
+impl ChairEntry {
+    /// 稼働を止めても割り当て済みのライドは最後まで担当するので、割り当てを優先する
+    pub fn state(&self) -> ChairState {
+        match &self.ride_id {
+            Some(ride_id) => ChairState::Assigned(ride_id.clone()),
+            None if !self.is_active => ChairState::Inactive,
+            None if self.consecutive_timeouts >= MAX_CONSECUTIVE_TIMEOUTS => {
+                ChairState::Unresponsive
+            }
+            None => ChairState::Free,
+        }
+    }
+}
+
+/// 椅子の状態の変更。他のインスタンスの `ChairRegistry` にも同じ変更を当てるために中継する
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type")]
+pub enum RegistryUpdate {
+    Register {
+        chair_id: String,
+        name: String,
+        model: String,
+        speed: i32,
+    },
+    SetActive {
+        chair_id: String,
+        is_active: bool,
+    },
+    UpdateLocation {
+        chair_id: String,
+        location: Coordinate,
+    },
+    Assign {
+        chair_id: String,
+        ride_id: String,
+    },
+    Acknowledge {
+        chair_id: String,
+        ride_id: String,
+    },
+    RecordTimeout {
+        chair_id: String,
+    },
+    Release {
+        chair_id: String,
+        ride_id: String,
+    },
+    /// 初期化で DB が作り直されたので、DB から読み直す
+    Reload,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ChairEntryRow {
+    id: String,
+    name: String,
+    model: String,
+    speed: Option<i32>,
+    is_active: bool,
+    ride_id: Option<String>,
+    latitude: Option<i32>,
+    longitude: Option<i32>,
+}
+
+/// 椅子の状態と最新の位置をメモリ上で管理する。
+/// マッチングと近くの椅子の検索で毎回 DB を引かないためのもので、起動時と初期化時に DB から作り直す。
+/// 状態を変えたら、他のインスタンスにも同じ変更を中継する。
+///
+/// グリッドには位置が分かっている空いている椅子だけを入れ、状態が変わるたびに `sync_grid` で出し入れする。
+/// ロックは必ず `grid` → `chairs` の順に取る。`chairs` の読み取りロックを持ったまま `grid` のロックを取らないこと
+#[derive(Debug)]
+pub struct ChairRegistry {
+    /// 作り直すときに丸ごと差し替えるために `RwLock` で包む。普段は読み取りロックだけを取る
+    chairs: RwLock<DashMap<String, ChairEntry>>,
+    grid: RwLock<GridIndex>,
+    backend: Arc<dyn NotificationBackend>,
+}
+impl Default for ChairRegistry {
+    fn default() -> Self {
+        Self::new(Arc::new(InMemoryBackend))
+    }
+}
+impl ChairRegistry {
+    pub fn new(backend: Arc<dyn NotificationBackend>) -> Self {
+        Self {
+            chairs: RwLock::new(DashMap::new()),
+            grid: RwLock::new(GridIndex::new(GRID_CELL_SIZE)),
+            backend,
+        }
+    }
+
+    fn chairs(&self) -> RwLockReadGuard<'_, DashMap<String, ChairEntry>> {
+        self.chairs.read().unwrap()
+    }
+
+    /// 椅子の今の状態に合わせてグリッドに出し入れする。
+    /// グリッドのロックを取ってから状態を読むので、同時に状態が変わっても最後に呼ばれたものが最新の状態を反映する
+    fn sync_grid(&self, chair_id: &str) {
+        let mut grid = self.grid.write().unwrap();
+        let location = self
+            .chairs()
+            .get(chair_id)
+            .filter(|entry| entry.state() == ChairState::Free)
+            .and_then(|entry| entry.location);
+        match location {
+            Some(location) => grid.upsert(chair_id, location),
+            None => grid.remove(chair_id),
+        }
+    }
+
+    /// このプロセスの状態だけを DB から作り直す
+    pub async fn load(&self, pool: &sqlx::MySqlPool) -> sqlx::Result<()> {
+        // 完了もキャンセルもしていないライドがあれば、それを割り当て済みのライドとみなす。
+        // 椅子が COMPLETED などの通知を確認したかどうかは待たない (再接続すれば送り直される)
+        let rows: Vec<ChairEntryRow> = sqlx::query_as(
+            r#"
+            SELECT
+                chairs.id
+                , chairs.name
+                , chairs.model
+                , chair_models.speed
+                , chairs.is_active
+                , (
+                    SELECT rides.id FROM rides
+                    WHERE rides.chair_id = chairs.id
+                    AND NOT EXISTS (
+                        SELECT 1 FROM ride_statuses
+                        WHERE ride_id = rides.id
+                        AND status IN ('COMPLETED', 'CANCELED')
+                    )
+                    ORDER BY rides.updated_at DESC LIMIT 1
+                ) AS ride_id
+                , chair_locations.latitude
+                , chair_locations.longitude
+            FROM
+                chairs
+                LEFT JOIN chair_models ON chairs.model = chair_models.name
+                LEFT JOIN chair_locations ON chair_locations.id = (
+                    SELECT id FROM chair_locations WHERE chair_id = chairs.id ORDER BY created_at DESC LIMIT 1
+                )
+            "#,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        // 作り直している間も検索できるように、新しい状態を作ってから差し替える
+        let mut grid = GridIndex::new(GRID_CELL_SIZE);
+        let chairs = DashMap::new();
+        for row in rows {
+            // chair_models に無いモデルの椅子はマッチングの対象にしない (登録時と同じ)
+            let Some(speed) = row.speed else {
+                warn!(
+                    chair_id = row.id,
+                    model = row.model,
+                    "chair model not found, skipping chair"
+                );
+                continue;
+            };
+            let location = row
+                .latitude
+                .zip(row.longitude)
+                .map(|(latitude, longitude)| Coordinate {
+                    latitude,
+                    longitude,
+                });
+            let entry = ChairEntry {
+                name: row.name,
+                model: row.model,
+                speed,
+                is_active: row.is_active,
+                // 受理済みかどうかは分からないので、期限切れの確認時に DB で確かめる
+                acknowledged: false,
+                assigned_at: row.ride_id.as_ref().map(|_| Instant::now()),
+                ride_id: row.ride_id,
+                location,
+                consecutive_timeouts: 0,
+            };
+            if let Some(location) = location.filter(|_| entry.state() == ChairState::Free) {
+                grid.upsert(&row.id, location);
+            }
+            chairs.insert(row.id, entry);
+        }
+
+        let mut current_grid = self.grid.write().unwrap();
+        let mut current_chairs = self.chairs.write().unwrap();
+        *current_grid = grid;
+        *current_chairs = chairs;
+        Ok(())
+    }
+
+    /// DB から作り直し、他のインスタンスにも読み直させる
+    pub async fn reload(&self, pool: &sqlx::MySqlPool) -> sqlx::Result<()> {
+        self.load(pool).await?;
+        self.backend
+            .publish(RelayMessage::Registry(RegistryUpdate::Reload));
+        Ok(())
+    }
+
+    /// 他のインスタンスから届いた変更を当てる
+    pub async fn apply_relayed(
+        &self,
+        pool: &sqlx::MySqlPool,
+        update: RegistryUpdate,
+    ) -> sqlx::Result<()> {
+        match update {
+            RegistryUpdate::Reload => self.load(pool).await,
+            update => {
+                self.apply(&update);
+                Ok(())
+            }
+        }
+    }
+
+    /// このプロセスに当ててから、他のインスタンスに中継する
+    fn update(&self, update: RegistryUpdate) {
+        self.apply(&update);
+        self.backend.publish(RelayMessage::Registry(update));
+    }
+
+    fn apply(&self, update: &RegistryUpdate) {
+        match update {
+            RegistryUpdate::Register {
+                chair_id,
+                name,
+                model,
+                speed,
+            } => {
+                self.chairs().insert(
+                    chair_id.clone(),
+                    ChairEntry {
+                        name: name.clone(),
+                        model: model.clone(),
+                        speed: *speed,
+                        is_active: false,
+                        ride_id: None,
+                        acknowledged: false,
+                        assigned_at: None,
+                        location: None,
+                        consecutive_timeouts: 0,
+                    },
+                );
+            }
+            RegistryUpdate::SetActive {
+                chair_id,
+                is_active,
+            } => {
+                if let Some(mut entry) = self.chairs().get_mut(chair_id) {
+                    entry.is_active = *is_active;
+                    entry.consecutive_timeouts = 0;
+                }
+                self.sync_grid(chair_id);
+            }
+            RegistryUpdate::UpdateLocation { chair_id, location } => {
+                if let Some(mut entry) = self.chairs().get_mut(chair_id) {
+                    entry.location = Some(*location);
+                    entry.consecutive_timeouts = 0;
+                }
+                self.sync_grid(chair_id);
+            }
+            RegistryUpdate::Assign { chair_id, ride_id } => {
+                if let Some(mut entry) = self.chairs().get_mut(chair_id) {
+                    entry.ride_id = Some(ride_id.clone());
+                    entry.acknowledged = false;
+                    entry.assigned_at = Some(Instant::now());
+                }
+                self.sync_grid(chair_id);
+            }
+            RegistryUpdate::Acknowledge { chair_id, ride_id } => {
+                if let Some(mut entry) = self.chairs().get_mut(chair_id) {
+                    if entry.ride_id.as_ref() == Some(ride_id) {
+                        entry.acknowledged = true;
+                        entry.consecutive_timeouts = 0;
+                    }
+                }
+            }
+            RegistryUpdate::RecordTimeout { chair_id } => {
+                if let Some(mut entry) = self.chairs().get_mut(chair_id) {
+                    entry.consecutive_timeouts += 1;
+                    if entry.consecutive_timeouts == MAX_CONSECUTIVE_TIMEOUTS {
+                        warn!(
+                            chair_id,
+                            "chair keeps timing out, excluding it from matching"
+                        );
+                    }
+                }
+                self.sync_grid(chair_id);
+            }
+            RegistryUpdate::Release { chair_id, ride_id } => {
+                if let Some(mut entry) = self.chairs().get_mut(chair_id) {
+                    if entry.ride_id.as_ref() == Some(ride_id) {
+                        entry.ride_id = None;
+                        entry.acknowledged = false;
+                        entry.assigned_at = None;
+                    }
+                }
+                self.sync_grid(chair_id);
+            }
+            // DB を読むので `apply_relayed` で扱う
+            RegistryUpdate::Reload => {}
+        }
+    }
+
+    pub fn register(&self, chair_id: String, name: String, model: String, speed: i32) {
+        self.update(RegistryUpdate::Register {
+            chair_id,
+            name,
+            model,
+            speed,
+        });
+    }
+
+    pub fn get(&self, chair_id: &str) -> Option<ChairEntry> {
+        self.chairs().get(chair_id).map(|entry| entry.clone())
+    }
+
+    pub fn set_active(&self, chair_id: &str, is_active: bool) {
+        self.update(RegistryUpdate::SetActive {
+            chair_id: chair_id.to_owned(),
+            is_active,
+        });
+    }
+
+    pub fn update_location(&self, chair_id: &str, location: Coordinate) {
+        self.update(RegistryUpdate::UpdateLocation {
+            chair_id: chair_id.to_owned(),
+            location,
+        });
+    }
+
+    pub fn assign(&self, chair_id: &str, ride_id: &str) {
+        self.update(RegistryUpdate::Assign {
+            chair_id: chair_id.to_owned(),
+            ride_id: ride_id.to_owned(),
+        });
+    }
+
+    pub fn acknowledge(&self, chair_id: &str, ride_id: &str) {
+        self.update(RegistryUpdate::Acknowledge {
+            chair_id: chair_id.to_owned(),
+            ride_id: ride_id.to_owned(),
+        });
+    }
+
+    /// 割り当てに応答しなかったことを記録する。続けば椅子をマッチングの対象から外す
+    pub fn record_timeout(&self, chair_id: &str) {
+        self.update(RegistryUpdate::RecordTimeout {
+            chair_id: chair_id.to_owned(),
+        });
+    }
+
+    /// 割り当てから `timeout` 以上経っても受理されていない `(椅子ID, ライドID)` の一覧
+    pub fn unacknowledged_since(&self, timeout: Duration) -> Vec<(String, String)> {
+        self.chairs()
+            .iter()
+            .filter(|entry| {
+                !entry.acknowledged
+                    && entry
+                        .assigned_at
+                        .is_some_and(|assigned_at| assigned_at.elapsed() >= timeout)
+            })
+            .filter_map(|entry| Some((entry.key().clone(), entry.ride_id.clone()?)))
+            .collect()
+    }
+
+    /// `ride_id` がまだ割り当てられている場合のみ椅子を空きに戻す
+    pub fn release(&self, chair_id: &str, ride_id: &str) {
+        self.update(RegistryUpdate::Release {
+            chair_id: chair_id.to_owned(),
+            ride_id: ride_id.to_owned(),
+        });
+    }
+
+    /// 位置が分かっている空いている椅子の一覧
+    pub fn free_entries(&self) -> Vec<(String, ChairEntry)> {
+        self.chairs()
+            .iter()
+            .filter(|entry| entry.state() == ChairState::Free && entry.location.is_some())
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    /// `center` からマンハッタン距離 `distance` 以内の空いている椅子を近い順に返す
+    pub fn nearby_free_chairs(
+        &self,
+        center: Coordinate,
+        distance: i32,
+        limit: Option<usize>,
+    ) -> Vec<(String, ChairEntry)> {
+        let found = self.grid.read().unwrap().within(center, distance, limit);
+        found
+            .into_iter()
+            .filter_map(|(chair_id, _, _)| {
+                let entry = self.get(&chair_id)?;
+                Some((chair_id, entry))
+            })
+            .collect()
+    }
+
+    pub fn free_chairs(&self) -> Vec<FreeChair> {
+        self.free_entries()
+            .into_iter()
+            .filter_map(|(id, entry)| {
+                Some(FreeChair {
+                    id,
+                    speed: entry.speed,
+                    location: entry.location?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct RecordingBackend(Mutex<Vec<RelayMessage>>);
+    impl NotificationBackend for RecordingBackend {
+        fn publish(&self, message: RelayMessage) {
+            self.0.lock().unwrap().push(message);
+        }
+    }
+
+    #[test]
+    fn relayed_updates_bring_other_instances_to_the_same_state() {
+        let backend = Arc::new(RecordingBackend::default());
+        let local = ChairRegistry::new(backend.clone());
+        let remote = ChairRegistry::default();
+        let location = Coordinate {
+            latitude: 10,
+            longitude: 10,
+        };
+
+        local.register("chair".to_owned(), "name".to_owned(), "model".to_owned(), 3);
+        local.set_active("chair", true);
+        local.update_location("chair", location);
+        local.assign("chair", "ride");
+        local.acknowledge("chair", "ride");
+        for message in backend.0.lock().unwrap().drain(..) {
+            let RelayMessage::Registry(update) = message else {
+                panic!("unexpected message: {message:?}");
+            };
+            remote.apply(&update);
+        }
+
+        let entry = remote.get("chair").unwrap();
+        assert_eq!(entry.state(), ChairState::Assigned("ride".to_owned()));
+        assert!(entry.acknowledged);
+        assert_eq!(entry.location, Some(location));
+        assert!(remote.nearby_free_chairs(location, 0, None).is_empty());
+
+        local.release("chair", "ride");
+        for message in backend.0.lock().unwrap().drain(..) {
+            if let RelayMessage::Registry(update) = message {
+                remote.apply(&update);
+            }
+        }
+        assert_eq!(remote.nearby_free_chairs(location, 0, None).len(), 1);
+    }
+}
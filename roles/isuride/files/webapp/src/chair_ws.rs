@@ -0,0 +1,164 @@
+//! 椅子向けの WebSocket。
+//!
+//! 1 本の接続で位置の送信とライドの状態の更新を受け付け、`/api/chair/notification` と同じ通知を送る。
+//! 処理は HTTP のハンドラーと共通で、認証は接続を開くときに一度だけ行う。
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::response::Response;
+use futures::StreamExt as _;
+use tracing::{info, warn};
+
+use crate::chair_handlers::{ChairGetNotificationResponseData, ChairPostCoordinateResponse};
+use crate::events::Audience;
+use crate::models::Chair;
+use crate::{AppState, Coordinate, Error};
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ChairWsQuery {
+    /// ブラウザの WebSocket はヘッダーを付けられないので、`Last-Event-ID` の代わりにクエリでも受け付ける
+    last_event_id: Option<String>,
+}
+
+/// 椅子から送られてくるメッセージ
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ChairWsRequest {
+    Coordinate(Coordinate),
+    RideStatus { ride_id: String, status: String },
+    Ack { id: String },
+}
+
+/// 椅子に送るメッセージ
+#[derive(Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ChairWsResponse {
+    /// SSE のイベントと同じく、`id` は ride_statuses の ID
+    Notification {
+        id: Option<String>,
+        data: Option<ChairGetNotificationResponseData>,
+    },
+    CoordinateRecorded(ChairPostCoordinateResponse),
+    RideStatusUpdated {
+        ride_id: String,
+        status: String,
+    },
+    Acknowledged {
+        id: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+pub async fn chair_get_ws(
+    State(state): State<AppState>,
+    axum::Extension(chair): axum::Extension<Chair>,
+    Query(query): Query<ChairWsQuery>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let last_event_id = query
+        .last_event_id
+        .or_else(|| crate::app_handlers::last_event_id(&headers));
+    ws.on_upgrade(move |socket| async move {
+        if let Err(e) = serve_chair_ws(socket, state, chair, last_event_id).await {
+            warn!(e = e.to_string(), "chair websocket closed with error");
+        }
+    })
+}
+
+async fn serve_chair_ws(
+    mut socket: WebSocket,
+    state: AppState,
+    chair: Chair,
+    last_event_id: Option<String>,
+) -> Result<(), Error> {
+    info!(chair_id = chair.id, "open new websocket for chair");
+    let notifications =
+        crate::chair_handlers::subscribe_chair_notifications(&state, &chair.id, last_event_id)
+            .await?;
+    let mut notifications = std::pin::pin!(notifications);
+
+    loop {
+        let response = tokio::select! {
+            notification = notifications.next() => match notification {
+                Some(Ok(Some((id, data)))) => ChairWsResponse::Notification {
+                    id: Some(id),
+                    data: Some(data),
+                },
+                Some(Ok(None)) => ChairWsResponse::Notification { id: None, data: None },
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => handle_request(&state, &chair, &text).await,
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                // ping には axum が pong を返すので何もしない
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => {
+                    info!(chair_id = chair.id, e = e.to_string(), "websocket disconnected");
+                    return Ok(());
+                }
+            },
+        };
+
+        let text = serde_json::to_string(&response).unwrap();
+        if socket.send(Message::Text(text)).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// 椅子からのメッセージを HTTP のハンドラーと同じ処理に渡す。
+/// 処理に失敗しても接続は切らず、エラーを送り返す
+async fn handle_request(state: &AppState, chair: &Chair, text: &str) -> ChairWsResponse {
+    let request: ChairWsRequest = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(e) => {
+            return ChairWsResponse::Error {
+                message: format!("invalid message: {e}"),
+            }
+        }
+    };
+
+    let result = match request {
+        ChairWsRequest::Coordinate(coordinate) => {
+            crate::chair_handlers::record_coordinate(state, chair, coordinate)
+                .await
+                .map(ChairWsResponse::CoordinateRecorded)
+        }
+        ChairWsRequest::RideStatus { ride_id, status } => match status.parse() {
+            Ok(parsed) => {
+                crate::chair_handlers::update_ride_status(state, chair, ride_id.clone(), parsed)
+                    .await
+                    .map(|()| ChairWsResponse::RideStatusUpdated { ride_id, status })
+            }
+            Err(e) => Err(e),
+        },
+        ChairWsRequest::Ack { id } => {
+            crate::events::acknowledge_ride_statuses(&state.pool, Audience::Chair, &chair.id, &id)
+                .await
+                .map_err(Error::from)
+                .and_then(|acknowledged| {
+                    if acknowledged {
+                        Ok(ChairWsResponse::Acknowledged { id })
+                    } else {
+                        Err(Error::NotFound("notification not found"))
+                    }
+                })
+        }
+    };
+
+    result.unwrap_or_else(|e| {
+        warn!(
+            chair_id = chair.id,
+            e = e.to_string(),
+            "websocket request failed"
+        );
+        ChairWsResponse::Error {
+            message: e.to_string(),
+        }
+    })
+}
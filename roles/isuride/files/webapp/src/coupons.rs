@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::models::{Coupon, DiscountType};
+use crate::Error;
+
+/// クーポンを付与するときの条件。付与したクーポンにはこの条件を書き写すので、
+/// 後から条件を変えても付与済みのクーポンには影響しない
+#[derive(Debug, Clone, Copy)]
+pub struct CouponRule {
+    pub campaign: &'static str,
+    pub discount_type: DiscountType,
+    /// FLAT なら割引額、PERCENTAGE なら割引率 (%)
+    pub amount: i32,
+    /// PERCENTAGE の割引額の上限
+    pub max_discount: Option<i32>,
+    /// 割引前の運賃がこれ未満のライドには使えない
+    pub min_fare: i32,
+    /// 付与してから使える日数。None なら期限なし
+    pub valid_days: Option<i64>,
+    /// 使えるクーポンが複数あるときは大きいものから使う
+    pub priority: i32,
+    /// 同じキャンペーンのクーポンを 1 人が使える回数
+    pub per_user_limit: Option<i32>,
+}
+
+/// 初回登録キャンペーン
+pub const NEW_USER_COUPON: CouponRule = CouponRule {
+    campaign: "NEW2024",
+    discount_type: DiscountType::Flat,
+    amount: 3000,
+    max_discount: None,
+    min_fare: 0,
+    valid_days: None,
+    priority: 100,
+    per_user_limit: Some(1),
+};
+
+/// 招待コードを使って登録したユーザーへのクーポン
+pub const INVITATION_COUPON: CouponRule = CouponRule {
+    campaign: "INVITATION",
+    discount_type: DiscountType::Flat,
+    amount: 1500,
+    max_discount: None,
+    min_fare: 0,
+    valid_days: None,
+    priority: 50,
+    per_user_limit: Some(1),
+};
+
+/// 招待した側へのクーポン
+pub const INVITER_REWARD_COUPON: CouponRule = CouponRule {
+    campaign: "REWARD",
+    discount_type: DiscountType::Flat,
+    amount: 1000,
+    max_discount: None,
+    min_fare: 0,
+    valid_days: None,
+    priority: 0,
+    per_user_limit: None,
+};
+
+pub async fn grant_coupon(
+    tx: &mut sqlx::MySqlConnection,
+    user_id: &str,
+    code: &str,
+    rule: &CouponRule,
+) -> sqlx::Result<()> {
+    let now = Utc::now();
+    let valid_until = rule.valid_days.map(|days| now + TimeDelta::days(days));
+    sqlx::query("INSERT INTO coupons (user_id, code, discount, campaign, discount_type, max_discount, min_fare, valid_from, valid_until, priority, per_user_limit) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+        .bind(user_id)
+        .bind(code)
+        .bind(rule.amount)
+        .bind(rule.campaign)
+        .bind(rule.discount_type)
+        .bind(rule.max_discount)
+        .bind(rule.min_fare)
+        .bind(now)
+        .bind(valid_until)
+        .bind(rule.priority)
+        .bind(rule.per_user_limit)
+        .execute(tx)
+        .await?;
+    Ok(())
+}
+
+impl Coupon {
+    /// 距離運賃が `metered_fare` のライドでの割引額。割引は距離運賃から引くので、割引率も距離運賃に掛ける
+    pub fn discount_for(&self, metered_fare: i32) -> i32 {
+        match self.discount_type {
+            DiscountType::Flat => self.discount,
+            DiscountType::Percentage => {
+                let discount = metered_fare * self.discount / 100;
+                self.max_discount
+                    .map_or(discount, |max_discount| discount.min(max_discount))
+            }
+        }
+    }
+
+    /// どうやって手に入れたクーポンか
+    pub fn origin(&self) -> &str {
+        match self.campaign.as_str() {
+            "NEW2024" => "signup",
+            "INVITATION" => "invitation",
+            "REWARD" => "reward",
+            campaign => campaign,
+        }
+    }
+
+    /// 期限と最低運賃の条件を満たしているか。使用回数の上限は `select_coupon` で見る
+    pub fn is_applicable(&self, fare: i32, now: DateTime<Utc>) -> bool {
+        self.used_by.is_none()
+            && self.valid_from.is_none_or(|valid_from| valid_from <= now)
+            && self.valid_until.is_none_or(|valid_until| now < valid_until)
+            && fare >= self.min_fare
+    }
+}
+
+/// 割引前の運賃が `fare` のライドで使うクーポンを選ぶ。予約時と見積もりの両方で使う。
+/// `for_update` のときは、そのまま使えるように候補のクーポンをロックする
+pub async fn select_coupon(
+    tx: &mut sqlx::MySqlConnection,
+    user_id: &str,
+    fare: i32,
+    for_update: bool,
+) -> sqlx::Result<Option<Coupon>> {
+    let query = if for_update {
+        "SELECT * FROM coupons WHERE user_id = ? AND used_by IS NULL ORDER BY priority DESC, created_at, code FOR UPDATE"
+    } else {
+        "SELECT * FROM coupons WHERE user_id = ? AND used_by IS NULL ORDER BY priority DESC, created_at, code"
+    };
+    let candidates: Vec<Coupon> = sqlx::query_as(query)
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+
+    let used_by_campaign = used_by_campaign(tx, user_id).await?;
+    let now = Utc::now();
+    Ok(candidates
+        .into_iter()
+        .find(|coupon| coupon.is_applicable(fare, now) && within_limit(coupon, &used_by_campaign)))
+}
+
+/// ライドを作るときにどのクーポンを使うか
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CouponChoice {
+    /// 使えるクーポンのうち優先度の高いものを使う
+    Auto,
+    /// クーポンを使わない
+    Skip,
+    /// 指定したクーポンを使う。使えなければエラーにする
+    Code(String),
+}
+impl CouponChoice {
+    pub fn from_request(coupon_code: Option<String>, skip_coupon: bool) -> Result<Self, Error> {
+        match (coupon_code, skip_coupon) {
+            (Some(_), true) => Err(Error::BadRequest(
+                "coupon_code and skip_coupon cannot be used together",
+            )),
+            (Some(code), false) => Ok(Self::Code(code)),
+            (None, true) => Ok(Self::Skip),
+            (None, false) => Ok(Self::Auto),
+        }
+    }
+}
+
+/// `select_coupon` と同じだが、ユーザーが選んだクーポンを優先する
+pub async fn choose_coupon(
+    tx: &mut sqlx::MySqlConnection,
+    user_id: &str,
+    fare: i32,
+    choice: &CouponChoice,
+    for_update: bool,
+) -> Result<Option<Coupon>, Error> {
+    let code = match choice {
+        CouponChoice::Auto => return Ok(select_coupon(tx, user_id, fare, for_update).await?),
+        CouponChoice::Skip => return Ok(None),
+        CouponChoice::Code(code) => code,
+    };
+
+    let query = if for_update {
+        "SELECT * FROM coupons WHERE user_id = ? AND code = ? FOR UPDATE"
+    } else {
+        "SELECT * FROM coupons WHERE user_id = ? AND code = ?"
+    };
+    let Some(coupon): Option<Coupon> = sqlx::query_as(query)
+        .bind(user_id)
+        .bind(code)
+        .fetch_optional(&mut *tx)
+        .await?
+    else {
+        return Err(Error::NotFound("coupon not found"));
+    };
+    let used_by_campaign = used_by_campaign(tx, user_id).await?;
+    if !coupon.is_applicable(fare, Utc::now()) || !within_limit(&coupon, &used_by_campaign) {
+        return Err(Error::BadRequest("coupon cannot be applied to this ride"));
+    }
+    Ok(Some(coupon))
+}
+
+/// キャンペーンごとの、ユーザーが使ったクーポンの数
+async fn used_by_campaign(
+    tx: &mut sqlx::MySqlConnection,
+    user_id: &str,
+) -> sqlx::Result<HashMap<String, i64>> {
+    let rows: Vec<(String, i64)> = sqlx::query_as(
+        "SELECT campaign, COUNT(*) FROM coupons WHERE user_id = ? AND used_by IS NOT NULL GROUP BY campaign",
+    )
+    .bind(user_id)
+    .fetch_all(tx)
+    .await?;
+    Ok(rows.into_iter().collect())
+}
+
+fn within_limit(coupon: &Coupon, used_by_campaign: &HashMap<String, i64>) -> bool {
+    let used = used_by_campaign.get(&coupon.campaign).copied().unwrap_or(0);
+    coupon
+        .per_user_limit
+        .is_none_or(|limit| used < i64::from(limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coupon(campaign: &str, discount_type: DiscountType, discount: i32) -> Coupon {
+        Coupon {
+            user_id: "user".to_owned(),
+            code: format!("{campaign}_CODE"),
+            discount,
+            created_at: DateTime::UNIX_EPOCH,
+            used_by: None,
+            campaign: campaign.to_owned(),
+            discount_type,
+            max_discount: None,
+            min_fare: 0,
+            valid_from: None,
+            valid_until: None,
+            priority: 0,
+            per_user_limit: None,
+        }
+    }
+
+    #[test]
+    fn flat_discount_ignores_the_fare() {
+        let coupon = coupon("FLAT", DiscountType::Flat, 300);
+        assert_eq!(coupon.discount_for(0), 300);
+        assert_eq!(coupon.discount_for(10_000), 300);
+    }
+
+    #[test]
+    fn percentage_discount_is_taken_of_the_metered_fare_and_capped() {
+        let mut coupon = coupon("PERCENT", DiscountType::Percentage, 20);
+        assert_eq!(coupon.discount_for(1000), 200);
+        // 端数は切り捨てる
+        assert_eq!(coupon.discount_for(999), 199);
+
+        coupon.max_discount = Some(150);
+        assert_eq!(coupon.discount_for(1000), 150);
+        assert_eq!(coupon.discount_for(500), 100);
+    }
+
+    #[test]
+    fn is_applicable_checks_the_validity_window() {
+        let now = Utc::now();
+        let mut coupon = coupon("WINDOW", DiscountType::Flat, 100);
+        coupon.valid_from = Some(now);
+        coupon.valid_until = Some(now + TimeDelta::days(1));
+
+        assert!(!coupon.is_applicable(1000, now - TimeDelta::seconds(1)));
+        assert!(coupon.is_applicable(1000, now));
+        assert!(coupon.is_applicable(1000, now + TimeDelta::hours(23)));
+        assert!(!coupon.is_applicable(1000, now + TimeDelta::days(1)));
+    }
+
+    #[test]
+    fn is_applicable_checks_min_fare_and_usage() {
+        let now = Utc::now();
+        let mut coupon = coupon("MIN", DiscountType::Flat, 100);
+        coupon.min_fare = 1000;
+        assert!(!coupon.is_applicable(999, now));
+        assert!(coupon.is_applicable(1000, now));
+
+        coupon.used_by = Some("ride".to_owned());
+        assert!(!coupon.is_applicable(1000, now));
+    }
+
+    #[test]
+    fn per_user_limit_counts_coupons_of_the_same_campaign() {
+        let mut limited = coupon("NEW2024", DiscountType::Flat, 3000);
+        limited.per_user_limit = Some(1);
+        let unlimited = coupon("REWARD", DiscountType::Flat, 1000);
+
+        let used_by_campaign = HashMap::new();
+        assert!(within_limit(&limited, &used_by_campaign));
+
+        // 他のキャンペーンのクーポンを使っていても数えない
+        let used_by_campaign = HashMap::from([("REWARD".to_owned(), 5)]);
+        assert!(within_limit(&limited, &used_by_campaign));
+        assert!(within_limit(&unlimited, &used_by_campaign));
+
+        let used_by_campaign = HashMap::from([("NEW2024".to_owned(), 1)]);
+        assert!(!within_limit(&limited, &used_by_campaign));
+
+        limited.per_user_limit = Some(2);
+        assert!(within_limit(&limited, &used_by_campaign));
+    }
+}
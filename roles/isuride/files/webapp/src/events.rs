@@ -0,0 +1,599 @@
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use sqlx::MySqlPool;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::models::{Chair, Ride, RideState, RideStatus, User};
+use crate::notification_backend::{NotificationBackend, RelayMessage};
+use crate::{Coordinate, Error};
+
+/// 購読者が読むのが遅れたときに溜めておくイベントの数。溢れたら購読者は DB から読み直す
+const CHANNEL_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct ChairStats {
+    pub total_rides_count: i32,
+    pub total_evaluation_avg: f64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ChairSummary {
+    pub id: String,
+    pub name: String,
+    pub model: String,
+    pub stats: ChairStats,
+}
+
+/// 通知を組み立てるのに必要なライドの情報。イベントを出す側が一度だけ DB から読む
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RideSnapshot {
+    pub ride_id: String,
+    pub user_id: String,
+    pub user_name: String,
+    pub chair: Option<ChairSummary>,
+    pub pickup: Coordinate,
+    pub destination: Coordinate,
+    pub fare: i32,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+impl RideSnapshot {
+    /// `refresh_stats` が false なら椅子の評価の集計は `chair_stats` に覚えているものを使う
+    pub async fn load(
+        tx: &mut sqlx::MySqlConnection,
+        ride_id: &str,
+        chair_stats: &ChairStatsCache,
+        refresh_stats: bool,
+    ) -> Result<Self, Error> {
+        let ride: Ride = sqlx::query_as("SELECT * FROM rides WHERE id = ?")
+            .bind(ride_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        let user: User = sqlx::query_as("SELECT * FROM users WHERE id = ?")
+            .bind(&ride.user_id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        let chair = if let Some(chair_id) = &ride.chair_id {
+            let chair: Chair = sqlx::query_as("SELECT * FROM chairs WHERE id = ?")
+                .bind(chair_id)
+                .fetch_one(&mut *tx)
+                .await?;
+            let stats = chair_stats.get(tx, &chair.id, refresh_stats).await?;
+            Some(ChairSummary {
+                id: chair.id,
+                name: chair.name,
+                model: chair.model,
+                stats,
+            })
+        } else {
+            None
+        };
+
+        let fare = crate::app_handlers::calculate_discounted_fare(tx, &ride).await?;
+
+        Ok(Self {
+            ride_id: ride.id,
+            user_id: user.id,
+            user_name: format!("{} {}", user.firstname, user.lastname),
+            chair,
+            pickup: Coordinate {
+                latitude: ride.pickup_latitude,
+                longitude: ride.pickup_longitude,
+            },
+            destination: Coordinate {
+                latitude: ride.destination_latitude,
+                longitude: ride.destination_longitude,
+            },
+            fare,
+            created_at: ride.created_at.timestamp_millis(),
+            updated_at: ride.updated_at.timestamp_millis(),
+        })
+    }
+
+    pub fn chair_id(&self) -> Option<&str> {
+        self.chair.as_ref().map(|chair| chair.id.as_str())
+    }
+}
+
+/// 椅子の評価の集計。ライドが COMPLETED になったときにしか変わらないので、そのときだけ DB から読み直す
+#[derive(Debug, Default)]
+pub struct ChairStatsCache(DashMap<String, ChairStats>);
+impl ChairStatsCache {
+    async fn get(
+        &self,
+        tx: &mut sqlx::MySqlConnection,
+        chair_id: &str,
+        refresh: bool,
+    ) -> Result<ChairStats, Error> {
+        if !refresh {
+            if let Some(stats) = self.0.get(chair_id) {
+                return Ok(*stats);
+            }
+        }
+        let stats = load_chair_stats(tx, chair_id).await?;
+        self.0.insert(chair_id.to_owned(), stats);
+        Ok(stats)
+    }
+
+    fn insert(&self, chair_id: &str, stats: ChairStats) {
+        self.0.insert(chair_id.to_owned(), stats);
+    }
+}
+
+async fn load_chair_stats(
+    tx: &mut sqlx::MySqlConnection,
+    chair_id: &str,
+) -> Result<ChairStats, Error> {
+    let rides: Vec<Ride> =
+        sqlx::query_as("SELECT * FROM rides WHERE chair_id = ? ORDER BY updated_at DESC")
+            .bind(chair_id)
+            .fetch_all(&mut *tx)
+            .await?;
+
+    let mut total_ride_count = 0;
+    let mut total_evaluation = 0.0;
+    for ride in rides {
+        let ride_statuses: Vec<RideStatus> =
+            sqlx::query_as("SELECT * FROM ride_statuses WHERE ride_id = ? ORDER BY created_at")
+                .bind(&ride.id)
+                .fetch_all(&mut *tx)
+                .await?;
+
+        if !ride_statuses
+            .iter()
+            .any(|status| status.status == RideState::Arrived)
+        {
+            continue;
+        }
+        if !ride_statuses
+            .iter()
+            .any(|status| status.status == RideState::Carrying)
+        {
+            continue;
+        }
+        let is_completed = ride_statuses
+            .iter()
+            .any(|status| status.status == RideState::Completed);
+        if !is_completed {
+            continue;
+        }
+
+        total_ride_count += 1;
+        total_evaluation += ride.evaluation.unwrap() as f64;
+    }
+
+    let total_evaluation_avg = if total_ride_count > 0 {
+        total_evaluation / total_ride_count as f64
+    } else {
+        0.0
+    };
+
+    Ok(ChairStats {
+        total_rides_count: total_ride_count,
+        total_evaluation_avg,
+    })
+}
+
+/// インスタンスの間で中継するので、送り先のプロセスで DB を引かずに通知を組み立てられる内容にする
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type")]
+pub enum RideEvent {
+    /// ライドへの椅子の割り当てが変わった。割り当てが外れたときは `ride.chair` が None になる。
+    /// 状態は変わらないので、`ride_status_id` は最新の状態の ID
+    RideAssigned {
+        ride_status_id: String,
+        status: RideState,
+        ride: Arc<RideSnapshot>,
+        /// 割り当てが外れた椅子。その椅子にも届けて、ライドを破棄させる
+        previous_chair_id: Option<String>,
+    },
+    StatusChanged {
+        ride_status_id: String,
+        /// 購読者はこれが最後に送った状態と一致しなければ、取りこぼしとみなして DB から読み直す
+        previous_ride_status_id: Option<String>,
+        status: RideState,
+        ride: Arc<RideSnapshot>,
+    },
+    LocationUpdated {
+        chair_id: String,
+        /// 椅子が担当中のライドのユーザー
+        user_id: Option<String>,
+        location: Coordinate,
+    },
+}
+
+/// ユーザーと椅子ごとにライドのイベントを配る
+#[derive(Debug)]
+pub struct EventBus {
+    by_user_id: DashMap<String, broadcast::Sender<RideEvent>>,
+    by_chair_id: DashMap<String, broadcast::Sender<RideEvent>>,
+    backend: Arc<dyn NotificationBackend>,
+    chair_stats: ChairStatsCache,
+}
+impl EventBus {
+    pub fn new(backend: Arc<dyn NotificationBackend>) -> Self {
+        Self {
+            by_user_id: DashMap::new(),
+            by_chair_id: DashMap::new(),
+            backend,
+            chair_stats: ChairStatsCache::default(),
+        }
+    }
+
+    pub fn subscribe_user(&self, user_id: &str) -> broadcast::Receiver<RideEvent> {
+        subscribe(&self.by_user_id, user_id)
+    }
+
+    pub fn subscribe_chair(&self, chair_id: &str) -> broadcast::Receiver<RideEvent> {
+        subscribe(&self.by_chair_id, chair_id)
+    }
+
+    /// このプロセスの購読者に配り、他のインスタンスにも中継する
+    pub fn publish(&self, event: RideEvent) {
+        self.backend.publish(RelayMessage::Ride(event.clone()));
+        self.deliver(event);
+    }
+
+    /// このプロセスの購読者にだけ配る。他のインスタンスから届いたイベントに使う
+    pub fn deliver(&self, event: RideEvent) {
+        // 他のインスタンスで COMPLETED になったライドの椅子の集計も、届いたイベントで入れ替える
+        if let RideEvent::StatusChanged {
+            status: RideState::Completed,
+            ride,
+            ..
+        } = &event
+        {
+            if let Some(chair) = &ride.chair {
+                self.chair_stats.insert(&chair.id, chair.stats);
+            }
+        }
+        if let RideEvent::RideAssigned {
+            previous_chair_id: Some(previous_chair_id),
+            ride,
+            ..
+        } = &event
+        {
+            if ride.chair_id() != Some(previous_chair_id.as_str()) {
+                send(&self.by_chair_id, previous_chair_id, event.clone());
+            }
+        }
+        let (user_id, chair_id) = match &event {
+            RideEvent::RideAssigned { ride, .. } | RideEvent::StatusChanged { ride, .. } => (
+                Some(ride.user_id.clone()),
+                ride.chair_id().map(ToOwned::to_owned),
+            ),
+            RideEvent::LocationUpdated {
+                chair_id, user_id, ..
+            } => (user_id.clone(), Some(chair_id.clone())),
+        };
+        if let Some(user_id) = user_id {
+            send(&self.by_user_id, &user_id, event.clone());
+        }
+        if let Some(chair_id) = chair_id {
+            send(&self.by_chair_id, &chair_id, event);
+        }
+    }
+
+    /// 購読者がいなくなったチャネルを捨てる。消したチャネルの数を返す
+    pub fn remove_idle_channels(&self) -> usize {
+        remove_idle(&self.by_user_id) + remove_idle(&self.by_chair_id)
+    }
+
+    pub fn stats(&self) -> EventBusStats {
+        EventBusStats {
+            users: ChannelStats::collect(&self.by_user_id),
+            chairs: ChannelStats::collect(&self.by_chair_id),
+        }
+    }
+
+    /// コミット済みのライドの状態の変化を配る。失敗してもリクエスト自体は成功しているので、ログに残すだけにする
+    pub async fn publish_status_changed(
+        &self,
+        pool: &MySqlPool,
+        ride_id: &str,
+        ride_status_id: String,
+        previous_ride_status_id: Option<String>,
+        status: RideState,
+    ) {
+        let result = async {
+            let mut conn = pool.acquire().await?;
+            RideSnapshot::load(
+                &mut conn,
+                ride_id,
+                &self.chair_stats,
+                status == RideState::Completed,
+            )
+            .await
+        }
+        .await;
+        match result {
+            Ok(ride) => self.publish(RideEvent::StatusChanged {
+                ride_status_id,
+                previous_ride_status_id,
+                status,
+                ride: Arc::new(ride),
+            }),
+            Err(e) => warn!(ride_id, e = e.to_string(), "failed to publish ride event"),
+        }
+    }
+
+    /// コミット済みの椅子の割り当ての変化を配る。割り当てを外したときは、外れた椅子を `previous_chair_id` に渡す
+    pub async fn publish_ride_assigned(
+        &self,
+        pool: &MySqlPool,
+        ride_id: &str,
+        previous_chair_id: Option<&str>,
+    ) {
+        let result = async {
+            let mut conn = pool.acquire().await?;
+            let ride = RideSnapshot::load(&mut conn, ride_id, &self.chair_stats, false).await?;
+            let latest: RideStatus = sqlx::query_as(
+                "SELECT * FROM ride_statuses WHERE ride_id = ? ORDER BY created_at DESC LIMIT 1",
+            )
+            .bind(ride_id)
+            .fetch_one(&mut *conn)
+            .await?;
+            Ok::<_, Error>((ride, latest))
+        }
+        .await;
+        match result {
+            Ok((ride, latest)) => self.publish(RideEvent::RideAssigned {
+                ride_status_id: latest.id,
+                status: latest.status,
+                ride: Arc::new(ride),
+                previous_chair_id: previous_chair_id.map(ToOwned::to_owned),
+            }),
+            Err(e) => warn!(ride_id, e = e.to_string(), "failed to publish ride event"),
+        }
+    }
+
+    /// `after` がこのライドの状態ならそれより後の状態を、そうでなければまだ受け取りが確認されていない状態を読む
+    pub async fn load_pending_ride_statuses(
+        &self,
+        pool: &MySqlPool,
+        audience: Audience,
+        ride_id: &str,
+        after: Option<&str>,
+    ) -> Result<PendingRideStatuses, Error> {
+        let mut tx = pool.begin().await?;
+
+        let ride = RideSnapshot::load(&mut tx, ride_id, &self.chair_stats, false).await?;
+        let ride_statuses: Vec<RideStatus> =
+            sqlx::query_as("SELECT * FROM ride_statuses WHERE ride_id = ? ORDER BY created_at, id")
+                .bind(ride_id)
+                .fetch_all(&mut *tx)
+                .await?;
+
+        tx.commit().await?;
+
+        let latest = ride_statuses.last().cloned();
+        let pending = match after.and_then(|after| ride_statuses.iter().position(|s| s.id == after))
+        {
+            Some(position) => ride_statuses[position + 1..].to_vec(),
+            None => ride_statuses
+                .into_iter()
+                .filter(|ride_status| match audience {
+                    Audience::App => ride_status.app_sent_at.is_none(),
+                    Audience::Chair => ride_status.chair_sent_at.is_none(),
+                })
+                .collect(),
+        };
+
+        Ok(PendingRideStatuses {
+            ride,
+            latest,
+            pending,
+        })
+    }
+}
+
+/// 通知の宛先。ride_statuses の `app_sent_at` と `chair_sent_at` のどちらに送信済みを記録するかが変わる
+#[derive(Debug, Clone, Copy)]
+pub enum Audience {
+    App,
+    Chair,
+}
+impl Audience {
+    fn acknowledged_at_column(self) -> &'static str {
+        match self {
+            Self::App => "app_sent_at",
+            Self::Chair => "chair_sent_at",
+        }
+    }
+
+    fn owner_column(self) -> &'static str {
+        match self {
+            Self::App => "user_id",
+            Self::Chair => "chair_id",
+        }
+    }
+}
+
+/// 受け取ったと確認された `ride_status_id` と、それより前の同じライドの状態を送信済みにする。
+/// ack と、再接続したクライアントの `Last-Event-ID` からだけ呼ぶ。送っただけの状態は送信済みにしないので、
+/// 確認される前に切れたら次の接続で送り直す。
+/// `owner_id` のユーザーか椅子のライドの状態でなければ false を返す
+pub async fn acknowledge_ride_statuses(
+    pool: &MySqlPool,
+    audience: Audience,
+    owner_id: &str,
+    ride_status_id: &str,
+) -> sqlx::Result<bool> {
+    let Some(target) = find_owned_ride_status(pool, audience, owner_id, ride_status_id).await?
+    else {
+        return Ok(false);
+    };
+    mark_ride_statuses_sent_until(pool, audience, &target).await?;
+    Ok(true)
+}
+
+async fn find_owned_ride_status(
+    pool: &MySqlPool,
+    audience: Audience,
+    owner_id: &str,
+    ride_status_id: &str,
+) -> sqlx::Result<Option<RideStatus>> {
+    sqlx::query_as(&format!(
+        "SELECT ride_statuses.* FROM ride_statuses JOIN rides ON rides.id = ride_statuses.ride_id WHERE ride_statuses.id = ? AND rides.{} = ?",
+        audience.owner_column()
+    ))
+    .bind(ride_status_id)
+    .bind(owner_id)
+    .fetch_optional(pool)
+    .await
+}
+
+async fn mark_ride_statuses_sent_until(
+    pool: &MySqlPool,
+    audience: Audience,
+    target: &RideStatus,
+) -> sqlx::Result<()> {
+    let column = audience.acknowledged_at_column();
+    sqlx::query(&format!(
+        "UPDATE ride_statuses SET {column} = CURRENT_TIMESTAMP(6) WHERE ride_id = ? AND created_at <= ? AND {column} IS NULL"
+    ))
+    .bind(&target.ride_id)
+    .bind(target.created_at)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// ライドの状態のうち、まだ受け取りが確認されていないものを古い順に並べたもの
+#[derive(Debug)]
+pub struct PendingRideStatuses {
+    pub ride: RideSnapshot,
+    /// ライドを作ったトランザクションがまだ状態を書いていなければ None
+    pub latest: Option<RideStatus>,
+    pub pending: Vec<RideStatus>,
+}
+
+fn subscribe(
+    senders: &DashMap<String, broadcast::Sender<RideEvent>>,
+    key: &str,
+) -> broadcast::Receiver<RideEvent> {
+    senders
+        .entry(key.to_owned())
+        .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+        .subscribe()
+}
+
+/// 購読を始めるときはエントリのロックを取るので、ここで消したチャネルに購読者が付くことはない
+fn remove_idle(senders: &DashMap<String, broadcast::Sender<RideEvent>>) -> usize {
+    let before = senders.len();
+    senders.retain(|_, sender| sender.receiver_count() > 0);
+    before.saturating_sub(senders.len())
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct EventBusStats {
+    pub users: ChannelStats,
+    pub chairs: ChannelStats,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ChannelStats {
+    /// 作られているチャネルの数
+    pub channels: usize,
+    /// 購読者が 1 つ以上いるチャネルの数
+    pub active_channels: usize,
+    /// 購読者の数の合計
+    pub subscribers: usize,
+}
+impl ChannelStats {
+    fn collect(senders: &DashMap<String, broadcast::Sender<RideEvent>>) -> Self {
+        let mut stats = Self {
+            channels: 0,
+            active_channels: 0,
+            subscribers: 0,
+        };
+        for sender in senders.iter() {
+            let receiver_count = sender.receiver_count();
+            stats.channels += 1;
+            stats.subscribers += receiver_count;
+            if receiver_count > 0 {
+                stats.active_channels += 1;
+            }
+        }
+        stats
+    }
+}
+
+/// 誰も購読していなければ捨てる。購読を始めるときは DB から読み直すので取りこぼしにはならない
+fn send(senders: &DashMap<String, broadcast::Sender<RideEvent>>, key: &str, event: RideEvent) {
+    if let Some(sender) = senders.get(key) {
+        let _ = sender.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notification_backend::InMemoryBackend;
+
+    fn snapshot(chair_id: Option<&str>) -> Arc<RideSnapshot> {
+        let at = Coordinate {
+            latitude: 0,
+            longitude: 0,
+        };
+        Arc::new(RideSnapshot {
+            ride_id: "ride".to_owned(),
+            user_id: "user".to_owned(),
+            user_name: "user".to_owned(),
+            chair: chair_id.map(|chair_id| ChairSummary {
+                id: chair_id.to_owned(),
+                name: chair_id.to_owned(),
+                model: "model".to_owned(),
+                stats: ChairStats {
+                    total_rides_count: 0,
+                    total_evaluation_avg: 0.0,
+                },
+            }),
+            pickup: at,
+            destination: at,
+            fare: 500,
+            created_at: 0,
+            updated_at: 0,
+        })
+    }
+
+    fn assigned(chair_id: Option<&str>, previous_chair_id: Option<&str>) -> RideEvent {
+        RideEvent::RideAssigned {
+            ride_status_id: "status".to_owned(),
+            status: RideState::Matching,
+            ride: snapshot(chair_id),
+            previous_chair_id: previous_chair_id.map(ToOwned::to_owned),
+        }
+    }
+
+    #[test]
+    fn unassignment_reaches_the_previous_chair() {
+        let bus = EventBus::new(Arc::new(InMemoryBackend));
+        let mut user = bus.subscribe_user("user");
+        let mut previous = bus.subscribe_chair("previous");
+        let mut next = bus.subscribe_chair("next");
+
+        bus.deliver(assigned(None, Some("previous")));
+        assert!(user.try_recv().is_ok());
+        assert!(previous.try_recv().is_ok());
+        assert!(next.try_recv().is_err());
+
+        bus.deliver(assigned(Some("next"), Some("previous")));
+        assert!(user.try_recv().is_ok());
+        assert!(previous.try_recv().is_ok());
+        assert!(next.try_recv().is_ok());
+    }
+
+    #[test]
+    fn reassignment_to_the_same_chair_is_delivered_once() {
+        let bus = EventBus::new(Arc::new(InMemoryBackend));
+        let mut chair = bus.subscribe_chair("chair");
+
+        bus.deliver(assigned(Some("chair"), Some("chair")));
+        assert!(chair.try_recv().is_ok());
+        assert!(chair.try_recv().is_err());
+    }
+}
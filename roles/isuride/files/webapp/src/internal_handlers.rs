@@ -1,72 +1,242 @@
-use axum::extract::State;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
-use tokio::sync::watch;
 use tracing::info;
 use ulid::Ulid;
 
-use crate::models::{Chair, Ride};
-use crate::{AppState, Error};
+use crate::events::EventBusStats;
+use crate::matching::{Assignment, PendingRide};
+use crate::models::{MatchingEvent, RefundRequester, Ride, RideState};
+use crate::payments::ReconciliationReport;
+use crate::refunds::{PostRefundRequest, RefundResponse};
+use crate::{AppState, Coordinate, Error};
 
 pub fn internal_routes() -> axum::Router<AppState> {
-    axum::Router::new().route(
-        "/api/internal/matching",
-        axum::routing::get(internal_get_matching),
-    )
+    axum::Router::new()
+        .route(
+            "/api/internal/matching",
+            axum::routing::get(internal_get_matching),
+        )
+        .route(
+            "/api/internal/payments/reconcile",
+            axum::routing::post(internal_post_payments_reconcile),
+        )
+        .route(
+            "/api/internal/rides/:ride_id/refund",
+            axum::routing::post(internal_post_ride_refund),
+        )
+        .route(
+            "/api/internal/subscriptions",
+            axum::routing::get(internal_get_subscriptions),
+        )
+}
+
+// 通知の購読状況。接続が切れたのにチャネルが残り続けていないかの確認に使う
+async fn internal_get_subscriptions(
+    State(AppState { event_bus, .. }): State<AppState>,
+) -> axum::Json<EventBusStats> {
+    axum::Json(event_bus.stats())
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct InternalPostPaymentsReconcileQuery {
+    user_id: Option<String>,
+    /// 真のときだけ請求し直す。既定では報告だけする
+    #[serde(default)]
+    repost: bool,
+}
+
+// 決済サービス上の請求と完了済みライドを突き合わせる。
+// `repost=true` なら、成功したと記録しているのに決済サービスに無いものだけ同じ支払い方法で送り直す
+async fn internal_post_payments_reconcile(
+    State(state): State<AppState>,
+    Query(query): Query<InternalPostPaymentsReconcileQuery>,
+) -> Result<axum::Json<Vec<ReconciliationReport>>, Error> {
+    let repost = query.repost;
+    let reports = if let Some(user_id) = query.user_id {
+        vec![
+            crate::payments::reconcile_user_payments(
+                &state,
+                &user_id,
+                crate::payments::retrieve_completed_rides,
+                repost,
+            )
+            .await?,
+        ]
+    } else {
+        crate::payments::reconcile_all_payments(&state, repost).await?
+    };
+    Ok(axum::Json(reports))
+}
+
+// 運営による返金。オーナーの返金と違い、どのライドでも返金できる
+async fn internal_post_ride_refund(
+    State(state): State<AppState>,
+    Path((ride_id,)): Path<(String,)>,
+    axum::Json(req): axum::Json<PostRefundRequest>,
+) -> Result<axum::Json<RefundResponse>, Error> {
+    let refund =
+        crate::refunds::issue_refund(&state, &ride_id, req, RefundRequester::Admin).await?;
+    Ok(axum::Json(refund))
 }
 
+/// 割り当てを外された椅子に同じライドを再び割り当てないでおく時間
+const MATCHING_EXCLUSION_TTL: Duration = Duration::from_secs(60);
+
 // このAPIをインスタンス内から一定間隔で叩かせることで、椅子とライドをマッチングさせる
 pub async fn internal_get_matching(
     State(AppState {
         pool,
-        ride_status_notify_by_chair_id,
-        ride_status_notify_by_user_id,
+        event_bus,
+        matching_strategy,
+        chair_registry,
+        ..
     }): State<AppState>,
 ) -> Result<StatusCode, Error> {
-    // MEMO: 一旦最も待たせているリクエストに適当な空いている椅子マッチさせる実装とする。おそらくもっといい方法があるはず…
-    let Some(ride): Option<Ride> =
-        sqlx::query_as("SELECT * FROM rides WHERE chair_id IS NULL ORDER BY created_at LIMIT 1")
-            .fetch_optional(&pool)
-            .await?
-    else {
+    let rides: Vec<Ride> = sqlx::query_as(
+        "SELECT * FROM rides WHERE chair_id IS NULL AND NOT EXISTS (SELECT 1 FROM ride_statuses WHERE ride_id = rides.id AND status = 'CANCELED') ORDER BY created_at",
+    )
+    .fetch_all(&pool)
+    .await?;
+    if rides.is_empty() {
         return Ok(StatusCode::NO_CONTENT);
-    };
+    }
+
+    let free_chairs = chair_registry.free_chairs();
+    if free_chairs.is_empty() {
+        return Ok(StatusCode::NO_CONTENT);
+    }
 
-    let matched: Vec<Chair> =
-        sqlx::query_as("SELECT chairs.*, chair_models.speed FROM chairs INNER JOIN chair_models ON chairs.model = chair_models.name WHERE chairs.is_active = TRUE ORDER BY chair_models.speed DESC LIMIT 10")
-            .fetch_all(&pool)
+    // 割り当てを外された椅子には、しばらく同じライドを割り当てない。
+    // 割り当てを外されたことのあるライドは待ち行列の先頭に戻す
+    let unassigned: Vec<(String, String, bool)> = sqlx::query_as(
+        "SELECT ride_matching_events.ride_id, ride_matching_events.chair_id, ride_matching_events.created_at > DATE_SUB(NOW(6), INTERVAL ? SECOND) FROM ride_matching_events INNER JOIN rides ON rides.id = ride_matching_events.ride_id WHERE rides.chair_id IS NULL",
+    )
+    .bind(MATCHING_EXCLUSION_TTL.as_secs())
+    .fetch_all(&pool)
+    .await?;
+    let mut requeued_ride_ids = HashSet::new();
+    let mut excluded_chair_ids_by_ride_id: HashMap<String, HashSet<String>> = HashMap::new();
+    for (ride_id, chair_id, excluded) in unassigned {
+        if excluded {
+            excluded_chair_ids_by_ride_id
+                .entry(ride_id.clone())
+                .or_default()
+                .insert(chair_id);
+        }
+        requeued_ride_ids.insert(ride_id);
+    }
+
+    let now = chrono::Utc::now();
+    let pending_rides: Vec<PendingRide> = rides
+        .iter()
+        .map(|ride| PendingRide {
+            id: ride.id.clone(),
+            pickup: Coordinate {
+                latitude: ride.pickup_latitude,
+                longitude: ride.pickup_longitude,
+            },
+            waiting_time: (now - ride.created_at).to_std().unwrap_or_default(),
+            requeued: requeued_ride_ids.contains(&ride.id),
+            excluded_chair_ids: excluded_chair_ids_by_ride_id
+                .remove(&ride.id)
+                .unwrap_or_default(),
+        })
+        .collect();
+    for Assignment { ride, chair } in matching_strategy.assign(&pending_rides, &free_chairs) {
+        let ride = &rides[ride];
+        let chair = &free_chairs[chair];
+
+        // 一覧を取ってから割り当てるまでにキャンセルされたライドには割り当てない
+        let result = sqlx::query("UPDATE rides SET chair_id = ? WHERE id = ? AND chair_id IS NULL AND NOT EXISTS (SELECT 1 FROM ride_statuses WHERE ride_id = rides.id AND status = 'CANCELED')")
+            .bind(&chair.id)
+            .bind(&ride.id)
+            .execute(&pool)
             .await?;
+        if result.rows_affected() == 0 {
+            continue;
+        }
+        chair_registry.assign(&chair.id, &ride.id);
 
-    for m in matched {
-        let empty: bool = sqlx::query_scalar(
-            "SELECT COUNT(*) = 0 FROM (SELECT COUNT(chair_sent_at) = 6 AS completed FROM ride_statuses WHERE ride_id IN (SELECT id FROM rides WHERE chair_id = ?) GROUP BY ride_id) is_completed WHERE completed = FALSE",
-        )
-        .bind(&m.id)
-        .fetch_one(&pool)
+        event_bus.publish_ride_assigned(&pool, &ride.id, None).await;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// 椅子からライドの割り当てを外し、その理由 (`REJECTED` か `TIMED_OUT`) を記録する。
+/// ライドがまだ `chair_id` に割り当てられていて MATCHING のままのときだけ外し、外したかどうかを返す。
+pub async fn unassign_ride(
+    tx: &mut sqlx::MySqlConnection,
+    ride_id: &str,
+    chair_id: &str,
+    event: MatchingEvent,
+) -> Result<bool, Error> {
+    let Some(ride): Option<Ride> = sqlx::query_as("SELECT * FROM rides WHERE id = ? FOR UPDATE")
+        .bind(ride_id)
+        .fetch_optional(&mut *tx)
+        .await?
+    else {
+        return Ok(false);
+    };
+    if ride.chair_id.as_deref() != Some(chair_id) {
+        return Ok(false);
+    }
+    let status = crate::get_latest_ride_status(&mut *tx, ride_id).await?;
+    if status != RideState::Matching {
+        return Ok(false);
+    }
+
+    sqlx::query("UPDATE rides SET chair_id = NULL WHERE id = ?")
+        .bind(ride_id)
+        .execute(&mut *tx)
         .await?;
+    sqlx::query(
+        "INSERT INTO ride_matching_events (id, ride_id, chair_id, event) VALUES (?, ?, ?, ?)",
+    )
+    .bind(Ulid::new().to_string())
+    .bind(ride_id)
+    .bind(chair_id)
+    .bind(event)
+    .execute(&mut *tx)
+    .await?;
 
-        if empty {
-            sqlx::query("UPDATE rides SET chair_id = ? WHERE id = ?")
-                .bind(m.id.clone())
-                .bind(ride.id)
-                .execute(&pool)
-                .await?;
-
-            ride_status_notify_by_chair_id
-                .entry(m.id.clone())
-                .or_insert_with(|| watch::channel(Ulid::new()))
-                .0
-                .send(Ulid::new())
-                .unwrap();
-            info!(chair_id = m.id, "notify chair change");
-            ride_status_notify_by_user_id
-                .entry(ride.user_id.clone())
-                .or_insert_with(|| watch::channel(Ulid::new()))
-                .0
-                .send(Ulid::new())
-                .unwrap();
-            break;
+    Ok(true)
+}
+
+/// 割り当てから `timeout` 以上経っても椅子が受理しないライドを割り当て直す
+pub async fn expire_unacknowledged_rides(
+    AppState {
+        pool,
+        event_bus,
+        matching_notify,
+        chair_registry,
+        ..
+    }: &AppState,
+    timeout: Duration,
+) -> Result<(), Error> {
+    for (chair_id, ride_id) in chair_registry.unacknowledged_since(timeout) {
+        let mut tx = pool.begin().await?;
+        let unassigned =
+            unassign_ride(&mut tx, &ride_id, &chair_id, MatchingEvent::TimedOut).await?;
+        tx.commit().await?;
+
+        if !unassigned {
+            // 既に受理されているか、割り当てが変わっている
+            chair_registry.acknowledge(&chair_id, &ride_id);
+            continue;
         }
+        info!(chair_id, ride_id, "ride acknowledgement timed out");
+        chair_registry.release(&chair_id, &ride_id);
+        chair_registry.record_timeout(&chair_id);
+
+        event_bus
+            .publish_ride_assigned(pool, &ride_id, Some(&chair_id))
+            .await;
+        matching_notify.notify_one();
     }
 
-    Ok(StatusCode::NO_CONTENT)
+    Ok(())
 }
@@ -1,17 +1,21 @@
 use std::sync::Arc;
 
 use axum::{http::StatusCode, response::Response};
-use dashmap::DashMap;
-use tokio::sync::watch;
+use tokio::sync::Notify;
 use ulid::Ulid;
 
 #[derive(Debug, Clone)]
 pub struct AppState {
     pub pool: sqlx::MySqlPool,
-    pub ride_status_notify_by_user_id:
-        Arc<DashMap<String, (watch::Sender<Ulid>, watch::Receiver<Ulid>)>>,
-    pub ride_status_notify_by_chair_id:
-        Arc<DashMap<String, (watch::Sender<Ulid>, watch::Receiver<Ulid>)>>,
+    pub event_bus: Arc<events::EventBus>,
+    pub matching_strategy: Arc<dyn matching::MatchingStrategy>,
+    /// マッチング対象が増えたときに matcher を起こす
+    pub matching_notify: Arc<Notify>,
+    pub chair_registry: Arc<chair_registry::ChairRegistry>,
+    /// 決済の請求が積まれたときに決済ワーカーを起こす
+    pub payment_notify: Arc<Notify>,
+    pub payment_gateway: Arc<payment_gateway::PaymentGatewayClient>,
+    pub quote_signer: Arc<quotes::QuoteSigner>,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -32,11 +36,18 @@ pub enum Error {
     NotFound(&'static str),
     #[error("{0}")]
     Conflict(&'static str),
+    #[error("invalid ride status transition: {from} -> {to}")]
+    InvalidRideStateTransition {
+        from: models::RideState,
+        to: models::RideState,
+    },
 }
 impl axum::response::IntoResponse for Error {
     fn into_response(self) -> Response {
         let status = match self {
-            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::BadRequest(_) | Self::InvalidRideStateTransition { .. } => {
+                StatusCode::BAD_REQUEST
+            }
             Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
             Self::NotFound(_) => StatusCode::NOT_FOUND,
             Self::Conflict(_) => StatusCode::CONFLICT,
@@ -55,7 +66,7 @@ impl axum::response::IntoResponse for Error {
     }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct Coordinate {
     pub latitude: i32,
     pub longitude: i32,
@@ -69,7 +80,10 @@ pub fn secure_random_str(b: usize) -> String {
     hex::encode(&buf)
 }
 
-pub async fn get_latest_ride_status<'e, E>(executor: E, ride_id: &str) -> sqlx::Result<String>
+pub async fn get_latest_ride_status<'e, E>(
+    executor: E,
+    ride_id: &str,
+) -> sqlx::Result<models::RideState>
 where
     E: 'e + sqlx::Executor<'e, Database = sqlx::MySql>,
 {
@@ -81,6 +95,48 @@ where
     .await
 }
 
+/// `transition_ride_status` で記録した状態と、その直前の状態の ID
+#[derive(Debug)]
+pub struct RideStatusTransition {
+    pub ride_status_id: String,
+    pub previous_ride_status_id: String,
+}
+
+/// 最新の状態から `next` に遷移できるか確かめてから、ライドの状態を記録する
+pub async fn transition_ride_status(
+    tx: &mut sqlx::MySqlConnection,
+    ride_id: &str,
+    next: models::RideState,
+) -> Result<RideStatusTransition, Error> {
+    let current: models::RideStatus = sqlx::query_as(
+        "SELECT * FROM ride_statuses WHERE ride_id = ? ORDER BY created_at DESC LIMIT 1",
+    )
+    .bind(ride_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    current.status.transition_to(next)?;
+    let ride_status_id = insert_ride_status(tx, ride_id, next).await?;
+    Ok(RideStatusTransition {
+        ride_status_id,
+        previous_ride_status_id: current.id,
+    })
+}
+
+pub async fn insert_ride_status(
+    tx: &mut sqlx::MySqlConnection,
+    ride_id: &str,
+    status: models::RideState,
+) -> sqlx::Result<String> {
+    let ride_status_id = Ulid::new().to_string();
+    sqlx::query("INSERT INTO ride_statuses (id, ride_id, status) VALUES (?, ?, ?)")
+        .bind(&ride_status_id)
+        .bind(ride_id)
+        .bind(status)
+        .execute(tx)
+        .await?;
+    Ok(ride_status_id)
+}
+
 // マンハッタン距離を求める
 pub fn calculate_distance(
     a_latitude: i32,
@@ -91,29 +147,21 @@ pub fn calculate_distance(
     (a_latitude - b_latitude).abs() + (a_longitude - b_longitude).abs()
 }
 
-const INITIAL_FARE: i32 = 500;
-const FARE_PER_DISTANCE: i32 = 100;
-
-pub fn calculate_fare(
-    pickup_latitude: i32,
-    pickup_longitude: i32,
-    dest_latitude: i32,
-    dest_longitude: i32,
-) -> i32 {
-    let metered_fare = FARE_PER_DISTANCE
-        * calculate_distance(
-            pickup_latitude,
-            pickup_longitude,
-            dest_latitude,
-            dest_longitude,
-        );
-    INITIAL_FARE + metered_fare
-}
-
 pub mod app_handlers;
 pub mod chair_handlers;
+pub mod chair_registry;
+pub mod chair_ws;
+pub mod coupons;
+pub mod events;
 pub mod internal_handlers;
+pub mod matching;
 pub mod middlewares;
 pub mod models;
+pub mod notification_backend;
 pub mod owner_handlers;
 pub mod payment_gateway;
+pub mod payments;
+pub mod pricing;
+pub mod quotes;
+pub mod refunds;
+pub mod spatial_index;
@@ -1,11 +1,18 @@
 use axum::extract::State;
-use dashmap::DashMap;
+use isuride::chair_registry::ChairRegistry;
+use isuride::events::EventBus;
 use isuride::internal_handlers;
+use isuride::notification_backend::{
+    BrokerBackend, InMemoryBackend, NotificationBackend, RelayMessage,
+};
+use isuride::payment_gateway::PaymentGatewayClient;
+use isuride::quotes::QuoteSigner;
 use isuride::{AppState, Error};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::sync::Notify;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -38,35 +45,139 @@ async fn main() -> anyhow::Result<()> {
         )
         .await?;
 
-    let ride_status_notify_by_chair_id = Arc::new(DashMap::new());
-    let ride_status_notify_by_user_id = Arc::new(DashMap::new());
+    let matching_strategy_name =
+        std::env::var("ISUCON_MATCHING_STRATEGY").unwrap_or_else(|_| "optimal".to_owned());
+    let matching_strategy = isuride::matching::strategy_from_name(&matching_strategy_name)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "unknown matching strategy in ISUCON_MATCHING_STRATEGY: {matching_strategy_name} (expected one of: {})",
+                isuride::matching::STRATEGY_NAMES.join(", ")
+            )
+        })?;
+
+    // 複数のインスタンスで動かすときは、ブローカー経由で通知のイベントと椅子の状態の変更を中継する
+    let (backend, incoming): (Arc<dyn NotificationBackend>, _) =
+        match std::env::var("ISUCON_NOTIFICATION_BROKER") {
+            Ok(addr) if !addr.is_empty() => {
+                let (backend, incoming) = BrokerBackend::connect(addr);
+                (Arc::new(backend), Some(incoming))
+            }
+            _ => (Arc::new(InMemoryBackend), None),
+        };
 
     let app_state = AppState {
-        pool: pool,
-        ride_status_notify_by_chair_id: ride_status_notify_by_chair_id.clone(),
-        ride_status_notify_by_user_id: ride_status_notify_by_user_id.clone(),
+        pool,
+        event_bus: Arc::new(EventBus::new(backend.clone())),
+        matching_strategy,
+        matching_notify: Arc::new(Notify::new()),
+        chair_registry: Arc::new(ChairRegistry::new(backend)),
+        payment_notify: Arc::new(Notify::new()),
+        payment_gateway: Arc::new(PaymentGatewayClient::new()),
+        quote_signer: Arc::new(QuoteSigner::from_env()),
     };
+    app_state.chair_registry.load(&app_state.pool).await?;
 
-    // yet another isuride-matcher
-    let pool = app_state.pool.clone();
+    if let Some(mut incoming) = incoming {
+        let state = app_state.clone();
+        tokio::spawn(async move {
+            while let Some(message) = incoming.recv().await {
+                match message {
+                    RelayMessage::Ride(event) => state.event_bus.deliver(event),
+                    RelayMessage::Registry(update) => {
+                        if let Err(e) = state
+                            .chair_registry
+                            .apply_relayed(&state.pool, update)
+                            .await
+                        {
+                            tracing::warn!(e = e.to_string(), "failed to reload chair registry");
+                        }
+                        // 他のインスタンスで空いた椅子もすぐにマッチングする
+                        state.matching_notify.notify_one();
+                    }
+                }
+            }
+        });
+    }
+
+    let matching_interval = std::env::var("ISUCON_MATCHING_INTERVAL")
+        .map(|interval_str| {
+            interval_str.parse().expect(
+                "failed to convert seconds from ISUCON_MATCHING_INTERVAL environment variable into f64",
+            )
+        })
+        .unwrap_or(0.5);
+    let matching_interval = Duration::from_secs_f64(matching_interval);
+    let accept_timeout = std::env::var("ISUCON_MATCHING_ACCEPT_TIMEOUT")
+        .map(|timeout_str| {
+            timeout_str.parse().expect(
+                "failed to convert seconds from ISUCON_MATCHING_ACCEPT_TIMEOUT environment variable into f64",
+            )
+        })
+        .unwrap_or(3.0);
+    let accept_timeout = Duration::from_secs_f64(accept_timeout);
+
+    // 接続が切れたユーザーや椅子の通知チャネルを片付ける
+    let event_bus = app_state.event_bus.clone();
     tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(30));
         loop {
-            let state = AppState {
-                pool: pool.clone(),
-                ride_status_notify_by_chair_id: ride_status_notify_by_chair_id.clone(),
-                ride_status_notify_by_user_id: ride_status_notify_by_user_id.clone(),
-            };
-            let _ = internal_handlers::internal_get_matching(axum::extract::State(state)).await;
-            let _ = tokio::time::sleep(Duration::from_millis(200));
+            interval.tick().await;
+            let removed = event_bus.remove_idle_channels();
+            if removed > 0 {
+                tracing::debug!(removed, "removed idle notification channels");
+            }
         }
     });
 
+    // マッチングと決済の処理は椅子の空き状況をプロセスごとに持っていたり二重に請求しかねないので、
+    // 複数のインスタンスで動かすときは ISUCON_BACKGROUND_WORKERS=0 にして 1 台でだけ動かす
+    let background_workers = !std::env::var("ISUCON_BACKGROUND_WORKERS").is_ok_and(|v| v == "0");
+    if background_workers {
+        // yet another isuride-matcher
+        // ライドの作成や椅子の解放で起こされるが、取りこぼしに備えて一定間隔でも実行する
+        let state = app_state.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = state.matching_notify.notified() => {}
+                    _ = tokio::time::sleep(matching_interval) => {}
+                }
+                let _ =
+                    internal_handlers::expire_unacknowledged_rides(&state, accept_timeout).await;
+                let _ =
+                    internal_handlers::internal_get_matching(axum::extract::State(state.clone()))
+                        .await;
+            }
+        });
+
+        tokio::spawn(isuride::payments::run_payment_worker(app_state.clone()));
+        tokio::spawn(isuride::refunds::run_refund_recovery(app_state.clone()));
+
+        let reconcile_interval = std::env::var("ISUCON_PAYMENT_RECONCILE_INTERVAL")
+            .map(|interval_str| {
+                interval_str.parse().expect(
+                    "failed to convert seconds from ISUCON_PAYMENT_RECONCILE_INTERVAL environment variable into f64",
+                )
+            })
+            .unwrap_or(60.0);
+        // 定期的な突き合わせは報告だけにする。請求し直すときは明示的に有効にする
+        let reconcile_repost =
+            std::env::var("ISUCON_PAYMENT_RECONCILE_REPOST").is_ok_and(|v| v == "1");
+        tokio::spawn(isuride::payments::run_payment_reconciler(
+            app_state.clone(),
+            Duration::from_secs_f64(reconcile_interval),
+            reconcile_repost,
+        ));
+    } else {
+        tracing::info!("background workers are disabled on this instance");
+    }
+
     let app = axum::Router::new()
         .route("/api/initialize", axum::routing::post(post_initialize))
         .merge(isuride::app_handlers::app_routes(app_state.clone()))
         .merge(isuride::owner_handlers::owner_routes(app_state.clone()))
         .merge(isuride::chair_handlers::chair_routes(app_state.clone()))
-        //.merge(isuride::internal_handlers::internal_routes())
+        .merge(isuride::internal_handlers::internal_routes())
         .with_state(app_state)
         .layer(tower_http::trace::TraceLayer::new_for_http());
 
@@ -92,7 +203,11 @@ struct PostInitializeResponse {
 }
 
 async fn post_initialize(
-    State(AppState { pool, .. }): State<AppState>,
+    State(AppState {
+        pool,
+        chair_registry,
+        ..
+    }): State<AppState>,
     axum::Json(req): axum::Json<PostInitializeRequest>,
 ) -> Result<axum::Json<PostInitializeResponse>, Error> {
     let output = tokio::process::Command::new("../sql/init.sh")
@@ -110,5 +225,7 @@ async fn post_initialize(
         .execute(&pool)
         .await?;
 
+    chair_registry.reload(&pool).await?;
+
     Ok(axum::Json(PostInitializeResponse { language: "rust" }))
 }
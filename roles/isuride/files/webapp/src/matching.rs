@@ -0,0 +1,372 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::Coordinate;
+
+/// マッチング待ちのライド。古い順に並んでいることを前提とする。
+/// どの戦略でも、割り当てを外されて戻ってきたライド (`requeued`) を先に扱う
+#[derive(Debug, Clone)]
+pub struct PendingRide {
+    pub id: String,
+    pub pickup: Coordinate,
+    /// ライドが作られてから待っている時間
+    pub waiting_time: Duration,
+    /// 椅子に拒否されたか、応答が無くて割り当てを外されたことがある
+    pub requeued: bool,
+    /// このライドを拒否したか、応答せずに割り当てを外されて間もない椅子
+    pub excluded_chair_ids: HashSet<String>,
+}
+impl PendingRide {
+    pub fn accepts(&self, chair: &FreeChair) -> bool {
+        !self.excluded_chair_ids.contains(&chair.id)
+    }
+}
+
+/// 空いている椅子とその最新の位置
+#[derive(Debug, Clone)]
+pub struct FreeChair {
+    pub id: String,
+    pub speed: i32,
+    pub location: Coordinate,
+}
+
+/// `rides[ride]` を `chairs[chair]` に割り当てることを表す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Assignment {
+    pub ride: usize,
+    pub chair: usize,
+}
+
+pub trait MatchingStrategy: Debug + Send + Sync {
+    fn assign(&self, rides: &[PendingRide], chairs: &[FreeChair]) -> Vec<Assignment>;
+}
+
+/// `ISUCON_MATCHING_STRATEGY` に指定できる名前
+pub const STRATEGY_NAMES: &[&str] = &["fifo", "nearest", "eta", "optimal"];
+
+/// `ISUCON_MATCHING_STRATEGY` で指定された名前からマッチング戦略を選ぶ
+pub fn strategy_from_name(name: &str) -> Option<Arc<dyn MatchingStrategy>> {
+    let strategy: Arc<dyn MatchingStrategy> = match name {
+        "fifo" => Arc::new(Fifo),
+        "nearest" => Arc::new(NearestChair),
+        "eta" => Arc::new(SpeedWeightedEta),
+        "optimal" => Arc::new(GlobalOptimal),
+        _ => return None,
+    };
+    Some(strategy)
+}
+
+fn pickup_distance(ride: &PendingRide, chair: &FreeChair) -> i32 {
+    crate::calculate_distance(
+        chair.location.latitude,
+        chair.location.longitude,
+        ride.pickup.latitude,
+        ride.pickup.longitude,
+    )
+}
+
+/// 迎車にかかる時間 (距離 / 速度)
+fn pickup_eta(ride: &PendingRide, chair: &FreeChair) -> i64 {
+    let distance = pickup_distance(ride, chair);
+    i64::from((distance + chair.speed - 1) / chair.speed)
+}
+
+/// 待っている 1 秒を迎車時間のいくつ分とみなすか。
+/// 遠い位置で待っているライドがいつまでも後回しにされないようにする
+const WAITING_TIME_WEIGHT: i64 = 1;
+
+/// 割り当てを外されたライドのコストから引く値。迎車時間や待ち時間の差よりも十分大きくする
+const REQUEUED_PRIORITY: i64 = 1 << 30;
+
+/// 全体で割り当てるときのコスト。待っている時間が長いライドほど安くし、割り当てを外されたライドは最優先にする
+fn assignment_cost(ride: &PendingRide, chair: &FreeChair) -> i64 {
+    let waiting_secs = i64::try_from(ride.waiting_time.as_secs())
+        .unwrap_or(i64::MAX)
+        .min(REQUEUED_PRIORITY / 2);
+    let priority = if ride.requeued { REQUEUED_PRIORITY } else { 0 };
+    pickup_eta(ride, chair) - WAITING_TIME_WEIGHT * waiting_secs - priority
+}
+
+/// 割り当てを外されたライドを先頭に、それ以外は古い順に並べたライドの番号
+fn queue_order(rides: &[PendingRide]) -> impl Iterator<Item = usize> + '_ {
+    let requeued = (0..rides.len()).filter(|&i| rides[i].requeued);
+    let others = (0..rides.len()).filter(|&i| !rides[i].requeued);
+    requeued.chain(others)
+}
+
+/// 待ち行列の先頭から順に、残っている椅子のうち `key` が最小のものを割り当てる
+fn greedy<K: Ord>(
+    rides: &[PendingRide],
+    chairs: &[FreeChair],
+    key: impl Fn(&PendingRide, &FreeChair) -> K,
+) -> Vec<Assignment> {
+    let mut used = vec![false; chairs.len()];
+    let mut assignments = Vec::new();
+    for ride_index in queue_order(rides) {
+        let ride = &rides[ride_index];
+        let Some(chair_index) = (0..chairs.len())
+            .filter(|&i| !used[i] && ride.accepts(&chairs[i]))
+            .min_by_key(|&i| key(ride, &chairs[i]))
+        else {
+            continue;
+        };
+        used[chair_index] = true;
+        assignments.push(Assignment {
+            ride: ride_index,
+            chair: chair_index,
+        });
+    }
+    assignments
+}
+
+/// 待ち行列の先頭から順に、最も速い椅子を割り当てる (元の実装と同じ方針)
+#[derive(Debug)]
+pub struct Fifo;
+impl MatchingStrategy for Fifo {
+    fn assign(&self, rides: &[PendingRide], chairs: &[FreeChair]) -> Vec<Assignment> {
+        greedy(rides, chairs, |_, chair| std::cmp::Reverse(chair.speed))
+    }
+}
+
+/// 待ち行列の先頭から順に、迎車位置に最も近い椅子を割り当てる
+#[derive(Debug)]
+pub struct NearestChair;
+impl MatchingStrategy for NearestChair {
+    fn assign(&self, rides: &[PendingRide], chairs: &[FreeChair]) -> Vec<Assignment> {
+        greedy(rides, chairs, pickup_distance)
+    }
+}
+
+/// 待ち行列の先頭から順に、迎車にかかる時間が最も短い椅子を割り当てる
+#[derive(Debug)]
+pub struct SpeedWeightedEta;
+impl MatchingStrategy for SpeedWeightedEta {
+    fn assign(&self, rides: &[PendingRide], chairs: &[FreeChair]) -> Vec<Assignment> {
+        greedy(rides, chairs, pickup_eta)
+    }
+}
+
+/// 迎車にかかる時間の合計が最小になるように全体で割り当てる。
+/// 椅子が足りないときは割り当てを外されたライド、次に待っている時間が長いライドを優先する
+#[derive(Debug)]
+pub struct GlobalOptimal;
+impl MatchingStrategy for GlobalOptimal {
+    fn assign(&self, rides: &[PendingRide], chairs: &[FreeChair]) -> Vec<Assignment> {
+        // 割り当てられない組には十分大きなコストを付けておき、結果から取り除く
+        const EXCLUDED_COST: i64 = 1 << 40;
+        let cost: Vec<Vec<i64>> = rides
+            .iter()
+            .map(|ride| {
+                chairs
+                    .iter()
+                    .map(|chair| {
+                        if ride.accepts(chair) {
+                            assignment_cost(ride, chair)
+                        } else {
+                            EXCLUDED_COST
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        min_cost_assignment(&cost)
+            .into_iter()
+            .filter(|&(ride, chair)| cost[ride][chair] < EXCLUDED_COST)
+            .map(|(ride, chair)| Assignment { ride, chair })
+            .collect()
+    }
+}
+
+/// 最小コスト割り当て問題をハンガリアン法で解く。
+///
+/// `cost[i][j]` は行 `i` (ライド) を列 `j` (椅子) に割り当てたときのコスト。
+/// 行数と列数が異なる場合は小さい方の数だけ割り当てを作り、`(行, 列)` の組を返す。
+pub fn min_cost_assignment(cost: &[Vec<i64>]) -> Vec<(usize, usize)> {
+    let rows = cost.len();
+    let cols = cost.first().map_or(0, Vec::len);
+    if rows == 0 || cols == 0 {
+        return Vec::new();
+    }
+
+    if rows <= cols {
+        hungarian(rows, cols, |i, j| cost[i][j])
+            .into_iter()
+            .enumerate()
+            .collect()
+    } else {
+        // 行の方が多い場合は転置して解く
+        hungarian(cols, rows, |i, j| cost[j][i])
+            .into_iter()
+            .enumerate()
+            .map(|(j, i)| (i, j))
+            .collect()
+    }
+}
+
+/// `n <= m` を前提に、各行に割り当てた列の番号を返す。O(n^2 m)
+fn hungarian(n: usize, m: usize, cost: impl Fn(usize, usize) -> i64) -> Vec<usize> {
+    const INF: i64 = i64::MAX / 4;
+
+    // 1-indexed で扱い、0 番目は番兵として使う
+    let mut u = vec![0i64; n + 1];
+    let mut v = vec![0i64; m + 1];
+    let mut p = vec![0usize; m + 1];
+    let mut way = vec![0usize; m + 1];
+
+    for i in 1..=n {
+        p[0] = i;
+        let mut j0 = 0;
+        let mut minv = vec![INF; m + 1];
+        let mut used = vec![false; m + 1];
+        loop {
+            used[j0] = true;
+            let i0 = p[j0];
+            let mut delta = INF;
+            let mut j1 = 0;
+            for j in 1..=m {
+                if used[j] {
+                    continue;
+                }
+                let cur = cost(i0 - 1, j - 1) - u[i0] - v[j];
+                if cur < minv[j] {
+                    minv[j] = cur;
+                    way[j] = j0;
+                }
+                if minv[j] < delta {
+                    delta = minv[j];
+                    j1 = j;
+                }
+            }
+            for j in 0..=m {
+                if used[j] {
+                    u[p[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    minv[j] -= delta;
+                }
+            }
+            j0 = j1;
+            if p[j0] == 0 {
+                break;
+            }
+        }
+        loop {
+            let j1 = way[j0];
+            p[j0] = p[j1];
+            j0 = j1;
+            if j0 == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut assignment = vec![0; n];
+    for j in 1..=m {
+        if p[j] != 0 {
+            assignment[p[j] - 1] = j - 1;
+        }
+    }
+    assignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn total_cost(cost: &[Vec<i64>], assignment: &[(usize, usize)]) -> i64 {
+        assignment.iter().map(|&(i, j)| cost[i][j]).sum()
+    }
+
+    #[test]
+    fn min_cost_assignment_finds_optimum() {
+        let cost = vec![vec![4, 1, 3], vec![2, 0, 5], vec![3, 2, 2]];
+        let mut assignment = min_cost_assignment(&cost);
+        assignment.sort();
+        assert_eq!(assignment, vec![(0, 1), (1, 0), (2, 2)]);
+        assert_eq!(total_cost(&cost, &assignment), 5);
+    }
+
+    #[test]
+    fn min_cost_assignment_with_more_rows_than_columns() {
+        let cost = vec![vec![1, 10], vec![10, 1], vec![5, 5]];
+        let mut assignment = min_cost_assignment(&cost);
+        assignment.sort();
+        assert_eq!(assignment, vec![(0, 0), (1, 1)]);
+    }
+
+    #[test]
+    fn min_cost_assignment_with_more_columns_than_rows() {
+        let cost = vec![vec![5, 1, 9], vec![2, 3, 1]];
+        let mut assignment = min_cost_assignment(&cost);
+        assignment.sort();
+        assert_eq!(assignment, vec![(0, 1), (1, 2)]);
+    }
+
+    #[test]
+    fn min_cost_assignment_with_empty_input() {
+        assert!(min_cost_assignment(&[]).is_empty());
+        assert!(min_cost_assignment(&[vec![], vec![]]).is_empty());
+    }
+
+    fn ride(id: &str, latitude: i32, waiting_secs: u64) -> PendingRide {
+        PendingRide {
+            id: id.to_owned(),
+            pickup: Coordinate {
+                latitude,
+                longitude: 0,
+            },
+            waiting_time: Duration::from_secs(waiting_secs),
+            requeued: false,
+            excluded_chair_ids: HashSet::new(),
+        }
+    }
+
+    fn chair(id: &str, latitude: i32) -> FreeChair {
+        FreeChair {
+            id: id.to_owned(),
+            speed: 1,
+            location: Coordinate {
+                latitude,
+                longitude: 0,
+            },
+        }
+    }
+
+    #[test]
+    fn global_optimal_prefers_nearby_rides_that_just_arrived() {
+        let rides = [ride("far", 30, 0), ride("near", 1, 0)];
+        let chairs = [chair("chair", 0)];
+        assert_eq!(
+            GlobalOptimal.assign(&rides, &chairs),
+            vec![Assignment { ride: 1, chair: 0 }]
+        );
+    }
+
+    #[test]
+    fn global_optimal_does_not_starve_long_waiting_rides() {
+        let rides = [ride("far", 30, 60), ride("near", 1, 0)];
+        let chairs = [chair("chair", 0)];
+        assert_eq!(
+            GlobalOptimal.assign(&rides, &chairs),
+            vec![Assignment { ride: 0, chair: 0 }]
+        );
+    }
+
+    #[test]
+    fn every_strategy_puts_requeued_rides_first() {
+        let mut requeued = ride("requeued", 30, 0);
+        requeued.requeued = true;
+        let rides = [ride("old", 1, 120), requeued];
+        let chairs = [chair("chair", 0)];
+        for name in STRATEGY_NAMES {
+            let strategy = strategy_from_name(name).unwrap();
+            assert_eq!(
+                strategy.assign(&rides, &chairs),
+                vec![Assignment { ride: 1, chair: 0 }],
+                "{name}"
+            );
+        }
+    }
+}
@@ -1,5 +1,38 @@
 use chrono::{DateTime, Utc};
 
+use crate::Error;
+
+/// `as_str` と `FromStr` を持つ enum を MySQL の ENUM / 文字列の列として読み書きできるようにする
+macro_rules! impl_sqlx_str {
+    ($ty:ty) => {
+        impl sqlx::Type<sqlx::MySql> for $ty {
+            fn type_info() -> sqlx::mysql::MySqlTypeInfo {
+                str::type_info()
+            }
+
+            fn compatible(ty: &sqlx::mysql::MySqlTypeInfo) -> bool {
+                <str as sqlx::Type<sqlx::MySql>>::compatible(ty)
+            }
+        }
+        impl sqlx::Encode<'_, sqlx::MySql> for $ty {
+            fn encode_by_ref(
+                &self,
+                buf: &mut Vec<u8>,
+            ) -> Result<sqlx::encode::IsNull, Box<dyn std::error::Error + Send + Sync>> {
+                <&str as sqlx::Encode<sqlx::MySql>>::encode(self.as_str(), buf)
+            }
+        }
+        impl sqlx::Decode<'_, sqlx::MySql> for $ty {
+            fn decode(
+                value: sqlx::mysql::MySqlValueRef,
+            ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+                let s = <&str as sqlx::Decode<sqlx::MySql>>::decode(value)?;
+                Ok(s.parse()?)
+            }
+        }
+    };
+}
+
 #[derive(Debug, Clone, sqlx::FromRow)]
 pub struct Chair {
     pub id: String,
@@ -36,11 +69,146 @@ pub struct User {
 
 #[derive(Debug, sqlx::FromRow)]
 pub struct PaymentToken {
+    pub id: String,
     pub user_id: String,
     pub token: String,
+    pub is_default: bool,
     pub created_at: DateTime<Utc>,
 }
 
+#[derive(Debug, sqlx::FromRow)]
+pub struct Payment {
+    pub id: String,
+    pub ride_id: String,
+    pub user_id: String,
+    pub amount: i32,
+    pub idempotency_key: String,
+    pub status: PaymentStatus,
+    pub payment_token_id: Option<String>,
+    pub charged_idempotency_key: Option<String>,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub claimed_by: Option<String>,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct Refund {
+    pub id: String,
+    pub ride_id: String,
+    pub payment_id: String,
+    pub amount: i32,
+    pub reason: String,
+    pub requested_by: RefundRequester,
+    pub idempotency_key: String,
+    pub status: RefundStatus,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// 支払いの状態。`payments.status` の ENUM と対応する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PaymentStatus {
+    Pending,
+    Processing,
+    Succeeded,
+    Failed,
+}
+impl PaymentStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "PENDING",
+            Self::Processing => "PROCESSING",
+            Self::Succeeded => "SUCCEEDED",
+            Self::Failed => "FAILED",
+        }
+    }
+}
+impl std::str::FromStr for PaymentStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "PENDING" => Ok(Self::Pending),
+            "PROCESSING" => Ok(Self::Processing),
+            "SUCCEEDED" => Ok(Self::Succeeded),
+            "FAILED" => Ok(Self::Failed),
+            _ => Err(format!("unknown payment status: {s}")),
+        }
+    }
+}
+impl_sqlx_str!(PaymentStatus);
+
+/// 返金の状態。`refunds.status` の ENUM と対応する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum RefundStatus {
+    Pending,
+    Succeeded,
+    Failed,
+    /// 結果が分からないまま送り直しを諦めた。返金されているかもしれないので、人が確かめるまで返金の枠を空けない
+    Unresolved,
+}
+impl RefundStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "PENDING",
+            Self::Succeeded => "SUCCEEDED",
+            Self::Failed => "FAILED",
+            Self::Unresolved => "UNRESOLVED",
+        }
+    }
+}
+impl std::str::FromStr for RefundStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "PENDING" => Ok(Self::Pending),
+            "SUCCEEDED" => Ok(Self::Succeeded),
+            "FAILED" => Ok(Self::Failed),
+            "UNRESOLVED" => Ok(Self::Unresolved),
+            _ => Err(format!("unknown refund status: {s}")),
+        }
+    }
+}
+impl_sqlx_str!(RefundStatus);
+
+/// 返金を求めた人。`refunds.requested_by` の ENUM と対応する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum RefundRequester {
+    Owner,
+    Admin,
+}
+impl RefundRequester {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Owner => "OWNER",
+            Self::Admin => "ADMIN",
+        }
+    }
+}
+impl std::str::FromStr for RefundRequester {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "OWNER" => Ok(Self::Owner),
+            "ADMIN" => Ok(Self::Admin),
+            _ => Err(format!("unknown refund requester: {s}")),
+        }
+    }
+}
+impl_sqlx_str!(RefundRequester);
+
 #[derive(Debug, sqlx::FromRow)]
 pub struct Ride {
     pub id: String,
@@ -50,16 +218,148 @@ pub struct Ride {
     pub pickup_longitude: i32,
     pub destination_latitude: i32,
     pub destination_longitude: i32,
+    /// ライドを作ったときに固定したサージの倍率
+    pub surge_multiplier: f64,
+    /// ライドを作ったときに固定した運賃。請求にはこれを使う。無ければ運賃を固定する前に作ったライド
+    pub quoted_fare: Option<i32>,
+    /// 完了したときに固定したオーナーの売上 (割引前の運賃)。無ければ売上を固定する前に完了したライド
+    pub sale: Option<i32>,
+    /// `sale` のうちサージの分
+    pub surge_sale: i32,
+    /// 作るのに使った見積もりの ID (`FareQuote::id`)
+    pub quote_id: Option<String>,
     pub evaluation: Option<i32>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Debug, sqlx::FromRow)]
+impl Ride {
+    pub fn pickup_coordinate(&self) -> crate::Coordinate {
+        crate::Coordinate {
+            latitude: self.pickup_latitude,
+            longitude: self.pickup_longitude,
+        }
+    }
+
+    pub fn destination_coordinate(&self) -> crate::Coordinate {
+        crate::Coordinate {
+            latitude: self.destination_latitude,
+            longitude: self.destination_longitude,
+        }
+    }
+}
+
+/// ライドの状態。`ride_statuses.status` の ENUM と対応する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum RideState {
+    Matching,
+    Enroute,
+    Pickup,
+    Carrying,
+    Arrived,
+    Completed,
+    Canceled,
+}
+impl RideState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Matching => "MATCHING",
+            Self::Enroute => "ENROUTE",
+            Self::Pickup => "PICKUP",
+            Self::Carrying => "CARRYING",
+            Self::Arrived => "ARRIVED",
+            Self::Completed => "COMPLETED",
+            Self::Canceled => "CANCELED",
+        }
+    }
+
+    /// 椅子が次のライドを受けられる状態かどうか
+    pub fn is_finished(self) -> bool {
+        matches!(self, Self::Completed | Self::Canceled)
+    }
+
+    /// ライドの状態遷移はすべてここで判定する
+    pub fn can_transition_to(self, next: Self) -> bool {
+        matches!(
+            (self, next),
+            (Self::Matching, Self::Enroute)
+                | (Self::Enroute, Self::Pickup)
+                | (Self::Pickup, Self::Carrying)
+                | (Self::Carrying, Self::Arrived)
+                | (Self::Arrived, Self::Completed)
+                | (Self::Matching | Self::Enroute, Self::Canceled)
+        )
+    }
+
+    pub fn transition_to(self, next: Self) -> Result<Self, Error> {
+        if self.can_transition_to(next) {
+            Ok(next)
+        } else {
+            Err(Error::InvalidRideStateTransition {
+                from: self,
+                to: next,
+            })
+        }
+    }
+}
+impl std::fmt::Display for RideState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+impl std::str::FromStr for RideState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "MATCHING" => Ok(Self::Matching),
+            "ENROUTE" => Ok(Self::Enroute),
+            "PICKUP" => Ok(Self::Pickup),
+            "CARRYING" => Ok(Self::Carrying),
+            "ARRIVED" => Ok(Self::Arrived),
+            "COMPLETED" => Ok(Self::Completed),
+            "CANCELED" => Ok(Self::Canceled),
+            _ => Err(format!("unknown ride status: {s}")),
+        }
+    }
+}
+impl_sqlx_str!(RideState);
+
+/// 椅子へのライドの割り当てが外れた理由。`ride_matching_events.event` の ENUM と対応する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchingEvent {
+    /// 椅子が拒否した
+    Rejected,
+    /// 椅子が時間内に受理しなかった
+    TimedOut,
+}
+impl MatchingEvent {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Rejected => "REJECTED",
+            Self::TimedOut => "TIMED_OUT",
+        }
+    }
+}
+impl std::str::FromStr for MatchingEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "REJECTED" => Ok(Self::Rejected),
+            "TIMED_OUT" => Ok(Self::TimedOut),
+            _ => Err(format!("unknown matching event: {s}")),
+        }
+    }
+}
+impl_sqlx_str!(MatchingEvent);
+
+#[derive(Debug, Clone, sqlx::FromRow)]
 pub struct RideStatus {
     pub id: String,
     pub ride_id: String,
-    pub status: String,
+    pub status: RideState,
     pub created_at: DateTime<Utc>,
     pub app_sent_at: Option<DateTime<Utc>>,
     pub chair_sent_at: Option<DateTime<Utc>>,
@@ -78,7 +378,132 @@ pub struct Owner {
 pub struct Coupon {
     pub user_id: String,
     pub code: String,
+    /// FLAT なら割引額、PERCENTAGE なら割引率 (%)
     pub discount: i32,
     pub created_at: DateTime<Utc>,
     pub used_by: Option<String>,
+    pub campaign: String,
+    pub discount_type: DiscountType,
+    pub max_discount: Option<i32>,
+    pub min_fare: i32,
+    pub valid_from: Option<DateTime<Utc>>,
+    pub valid_until: Option<DateTime<Utc>>,
+    pub priority: i32,
+    pub per_user_limit: Option<i32>,
+}
+
+/// クーポンの割引の種類。`coupons.discount_type` の ENUM と対応する
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum DiscountType {
+    Flat,
+    Percentage,
+}
+impl DiscountType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Flat => "FLAT",
+            Self::Percentage => "PERCENTAGE",
+        }
+    }
+}
+impl std::str::FromStr for DiscountType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "FLAT" => Ok(Self::Flat),
+            "PERCENTAGE" => Ok(Self::Percentage),
+            _ => Err(format!("unknown discount type: {s}")),
+        }
+    }
+}
+impl_sqlx_str!(DiscountType);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_STATES: [RideState; 7] = [
+        RideState::Matching,
+        RideState::Enroute,
+        RideState::Pickup,
+        RideState::Carrying,
+        RideState::Arrived,
+        RideState::Completed,
+        RideState::Canceled,
+    ];
+
+    #[test]
+    fn can_transition_to_allows_only_listed_transitions() {
+        use RideState::*;
+        let allowed = [
+            (Matching, Enroute),
+            (Enroute, Pickup),
+            (Pickup, Carrying),
+            (Carrying, Arrived),
+            (Arrived, Completed),
+            (Matching, Canceled),
+            (Enroute, Canceled),
+        ];
+        for from in ALL_STATES {
+            for to in ALL_STATES {
+                assert_eq!(
+                    from.can_transition_to(to),
+                    allowed.contains(&(from, to)),
+                    "{from} -> {to}"
+                );
+                assert_eq!(
+                    from.transition_to(to).is_ok(),
+                    from.can_transition_to(to),
+                    "{from} -> {to}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn finished_states_have_no_transitions() {
+        for from in [RideState::Completed, RideState::Canceled] {
+            assert!(from.is_finished());
+            assert!(ALL_STATES.iter().all(|&to| !from.can_transition_to(to)));
+        }
+    }
+
+    #[test]
+    fn rejection_is_not_a_ride_state() {
+        // 椅子による拒否は ride_matching_events に記録し、ライドは MATCHING のまま待ち行列に戻る
+        assert!("REJECTED".parse::<RideState>().is_err());
+        assert!(!RideState::Matching.can_transition_to(RideState::Matching));
+        for state in ALL_STATES {
+            assert_eq!(state.as_str().parse::<RideState>(), Ok(state));
+        }
+    }
+
+    #[test]
+    fn stored_enums_round_trip_through_their_column_values() {
+        for status in [
+            PaymentStatus::Pending,
+            PaymentStatus::Processing,
+            PaymentStatus::Succeeded,
+            PaymentStatus::Failed,
+        ] {
+            assert_eq!(status.as_str().parse::<PaymentStatus>(), Ok(status));
+        }
+        for status in [
+            RefundStatus::Pending,
+            RefundStatus::Succeeded,
+            RefundStatus::Failed,
+            RefundStatus::Unresolved,
+        ] {
+            assert_eq!(status.as_str().parse::<RefundStatus>(), Ok(status));
+        }
+        for requester in [RefundRequester::Owner, RefundRequester::Admin] {
+            assert_eq!(requester.as_str().parse::<RefundRequester>(), Ok(requester));
+        }
+        for event in [MatchingEvent::Rejected, MatchingEvent::TimedOut] {
+            assert_eq!(event.as_str().parse::<MatchingEvent>(), Ok(event));
+        }
+        assert!("CANCELED".parse::<PaymentStatus>().is_err());
+    }
 }
@@ -0,0 +1,150 @@
+//! 通知のイベントと椅子の状態の変更を他のインスタンスに中継する仕組み。
+//!
+//! `EventBus` はこのプロセスの購読者にイベントを配ったあと、バックエンドにも渡す。
+//! `ChairRegistry` もこのプロセスで状態を変えたあと、同じ変更をバックエンドに渡す。
+//! インスタンスが 1 つなら `InMemoryBackend` で何もしない。複数のインスタンスを動かすときは
+//! `BrokerBackend` で `notification_broker` に接続し、他のインスタンスで起きたものも受け取る。
+//!
+//! 中継できなかったイベントは捨てる。購読者は次の状態の変化で取りこぼしに気づいて DB から読み直す。
+//!
+//! マッチングと決済のワーカーは `ISUCON_BACKGROUND_WORKERS=0` にしていない 1 台でだけ動かす。
+//! 椅子の状態は中継されるので、API と通知はどのインスタンスで受けてもよい。
+
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::chair_registry::RegistryUpdate;
+use crate::events::RideEvent;
+
+/// ブローカーへの送信待ちのイベントの数。溢れたら捨てる
+const OUTGOING_CAPACITY: usize = 1024;
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+/// インスタンスの間で中継するもの
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub enum RelayMessage {
+    Ride(RideEvent),
+    Registry(RegistryUpdate),
+}
+
+pub trait NotificationBackend: Send + Sync + std::fmt::Debug {
+    /// このプロセスで起きたことを他のインスタンスに送る
+    fn publish(&self, message: RelayMessage);
+}
+
+/// 中継しない。インスタンスが 1 つのとき用
+#[derive(Debug, Default)]
+pub struct InMemoryBackend;
+impl NotificationBackend for InMemoryBackend {
+    fn publish(&self, _message: RelayMessage) {}
+}
+
+/// ブローカーとやりとりする 1 行分の JSON
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct RelayFrame {
+    /// 送ったインスタンス。ブローカーは送り主には返さないが、念のため自分のイベントは無視する
+    origin: String,
+    message: RelayMessage,
+}
+
+/// `notification_broker` を介して他のインスタンスとイベントをやりとりする
+#[derive(Debug)]
+pub struct BrokerBackend {
+    instance_id: String,
+    outgoing: mpsc::Sender<String>,
+}
+impl BrokerBackend {
+    /// ブローカーへの接続をバックグラウンドで保ち、他のインスタンスから届いたものを返す
+    pub fn connect(addr: String) -> (Self, mpsc::UnboundedReceiver<RelayMessage>) {
+        let instance_id = ulid::Ulid::new().to_string();
+        let (outgoing_tx, outgoing_rx) = mpsc::channel(OUTGOING_CAPACITY);
+        let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_connection(
+            addr,
+            instance_id.clone(),
+            outgoing_rx,
+            incoming_tx,
+        ));
+        (
+            Self {
+                instance_id,
+                outgoing: outgoing_tx,
+            },
+            incoming_rx,
+        )
+    }
+}
+impl NotificationBackend for BrokerBackend {
+    fn publish(&self, message: RelayMessage) {
+        let frame = RelayFrame {
+            origin: self.instance_id.clone(),
+            message,
+        };
+        let mut line = serde_json::to_string(&frame).unwrap();
+        line.push('\n');
+        if self.outgoing.try_send(line).is_err() {
+            warn!("notification relay queue is full, dropping event");
+        }
+    }
+}
+
+async fn run_connection(
+    addr: String,
+    instance_id: String,
+    mut outgoing: mpsc::Receiver<String>,
+    incoming: mpsc::UnboundedSender<RelayMessage>,
+) {
+    let mut backoff = Duration::from_millis(100);
+    loop {
+        match TcpStream::connect(&addr).await {
+            Ok(stream) => {
+                info!(addr, "connected to notification broker");
+                backoff = Duration::from_millis(100);
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                loop {
+                    tokio::select! {
+                        line = outgoing.recv() => {
+                            // EventBus が無くなったら終わる
+                            let Some(line) = line else {
+                                return;
+                            };
+                            if let Err(e) = writer.write_all(line.as_bytes()).await {
+                                warn!(addr, e = e.to_string(), "failed to relay event");
+                                break;
+                            }
+                        }
+                        line = lines.next_line() => match line {
+                            Ok(Some(line)) => match serde_json::from_str::<RelayFrame>(&line) {
+                                Ok(frame) if frame.origin != instance_id => {
+                                    if incoming.send(frame.message).is_err() {
+                                        return;
+                                    }
+                                }
+                                Ok(_) => {}
+                                Err(e) => warn!(e = e.to_string(), "invalid relayed event"),
+                            },
+                            Ok(None) => break,
+                            Err(e) => {
+                                warn!(addr, e = e.to_string(), "failed to read relayed event");
+                                break;
+                            }
+                        },
+                    }
+                }
+                warn!(addr, "disconnected from notification broker");
+            }
+            Err(e) => warn!(
+                addr,
+                e = e.to_string(),
+                "failed to connect to notification broker"
+            ),
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+}
@@ -1,12 +1,14 @@
 use std::collections::HashMap;
 
-use axum::extract::{Query, State};
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum_extra::extract::cookie::Cookie;
 use axum_extra::extract::CookieJar;
 use chrono::{DateTime, NaiveDate, Utc};
 
-use crate::models::{Chair, Owner, Ride};
+use crate::models::{Chair, Owner, RefundRequester, Ride};
+use crate::pricing::PricingEngine;
+use crate::refunds::{PostRefundRequest, RefundResponse};
 use crate::{AppState, Error};
 
 pub fn owner_routes(app_state: AppState) -> axum::Router<AppState> {
@@ -16,6 +18,10 @@ pub fn owner_routes(app_state: AppState) -> axum::Router<AppState> {
     let authed_routes = axum::Router::new()
         .route("/api/owner/sales", axum::routing::get(owner_get_sales))
         .route("/api/owner/chairs", axum::routing::get(owner_get_chairs))
+        .route(
+            "/api/owner/rides/:ride_id/refund",
+            axum::routing::post(owner_post_ride_refund),
+        )
         .route_layer(axum::middleware::from_fn_with_state(
             app_state.clone(),
             crate::middlewares::owner_auth_middleware,
@@ -73,17 +79,21 @@ struct ChairSales {
     id: String,
     name: String,
     sales: i32,
+    /// `sales` のうちサージで上乗せされた分
+    surge_sales: i32,
 }
 
 #[derive(Debug, serde::Serialize)]
 struct ModelSales {
     model: String,
     sales: i32,
+    surge_sales: i32,
 }
 
 #[derive(Debug, serde::Serialize)]
 struct OwnerGetSalesResponse {
     total_sales: i32,
+    total_surge_sales: i32,
     chairs: Vec<ChairSales>,
     models: Vec<ModelSales>,
 }
@@ -118,6 +128,7 @@ async fn owner_get_sales(
 
     let mut tx = pool.begin().await?;
 
+    let pricing = PricingEngine::load(&mut tx).await?;
     let chairs: Vec<Chair> = sqlx::query_as("SELECT * FROM chairs WHERE owner_id = ?")
         .bind(&owner.id)
         .fetch_all(&mut *tx)
@@ -125,6 +136,7 @@ async fn owner_get_sales(
 
     let mut res = OwnerGetSalesResponse {
         total_sales: 0,
+        total_surge_sales: 0,
         chairs: Vec::with_capacity(chairs.len()),
         models: Vec::new(),
     };
@@ -139,36 +151,65 @@ async fn owner_get_sales(
             .fetch_all(&mut *tx)
             .await?;
 
-        let sales = sum_sales(&reqs);
+        let refunded_by_ride_id =
+            crate::refunds::refunded_amounts_by_chair(&mut tx, &chair.id).await?;
+        let (sales, surge_sales) = sum_sales(&reqs, &chair, &pricing, &refunded_by_ride_id);
         res.total_sales += sales;
+        res.total_surge_sales += surge_sales;
 
         res.chairs.push(ChairSales {
             id: chair.id,
             name: chair.name,
             sales,
+            surge_sales,
         });
 
-        *model_sales_by_model.entry(chair.model).or_insert(0) += sales;
+        let model_sales = model_sales_by_model.entry(chair.model).or_insert((0, 0));
+        model_sales.0 += sales;
+        model_sales.1 += surge_sales;
     }
 
-    for (model, sales) in model_sales_by_model {
-        res.models.push(ModelSales { model, sales });
+    for (model, (sales, surge_sales)) in model_sales_by_model {
+        res.models.push(ModelSales {
+            model,
+            sales,
+            surge_sales,
+        });
     }
 
     Ok(axum::Json(res))
 }
 
-fn sum_sales(rides: &[Ride]) -> i32 {
-    rides.iter().map(calculate_sale).sum()
+/// (売上, そのうちサージの分)。返金された分はサージの分から先に差し引く
+fn sum_sales(
+    rides: &[Ride],
+    chair: &Chair,
+    pricing: &PricingEngine,
+    refunded_by_ride_id: &HashMap<String, i32>,
+) -> (i32, i32) {
+    rides
+        .iter()
+        .map(|ride| {
+            let refunded = refunded_by_ride_id.get(&ride.id).copied().unwrap_or(0);
+            let (sale, surge_sale) = calculate_sale(ride, chair, pricing);
+            ((sale - refunded).max(0), (surge_sale - refunded).max(0))
+        })
+        .fold((0, 0), |(sales, surge_sales), (sale, surge_sale)| {
+            (sales + sale, surge_sales + surge_sale)
+        })
 }
 
-fn calculate_sale(ride: &crate::models::Ride) -> i32 {
-    crate::calculate_fare(
-        ride.pickup_latitude,
-        ride.pickup_longitude,
-        ride.destination_latitude,
-        ride.destination_longitude,
-    )
+/// (完了したときに固定した売上, そのうちサージの分)。固定する前に完了したライドは今の料金設定で計算する
+fn calculate_sale(ride: &Ride, chair: &Chair, pricing: &PricingEngine) -> (i32, i32) {
+    match ride.sale {
+        Some(sale) => (sale, ride.surge_sale),
+        None => pricing.sale(
+            &chair.model,
+            ride.surge_multiplier,
+            ride.pickup_coordinate(),
+            ride.destination_coordinate(),
+        ),
+    }
 }
 
 /// MySQL で COUNT()、SUM() 等を使って DECIMAL 型の値になったものを i64 に変換するための構造体。
@@ -301,3 +342,26 @@ async fn owner_get_chairs(
             .collect(),
     }))
 }
+
+async fn owner_post_ride_refund(
+    State(state): State<AppState>,
+    axum::Extension(owner): axum::Extension<Owner>,
+    Path((ride_id,)): Path<(String,)>,
+    axum::Json(req): axum::Json<PostRefundRequest>,
+) -> Result<axum::Json<RefundResponse>, Error> {
+    // 自分の椅子が担当したライドだけ返金できる
+    let owns_ride: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM rides JOIN chairs ON chairs.id = rides.chair_id WHERE rides.id = ? AND chairs.owner_id = ?)",
+    )
+    .bind(&ride_id)
+    .bind(&owner.id)
+    .fetch_one(&state.pool)
+    .await?;
+    if !owns_ride {
+        return Err(Error::NotFound("ride not found"));
+    }
+
+    let refund =
+        crate::refunds::issue_refund(&state, &ride_id, req, RefundRequester::Owner).await?;
+    Ok(axum::Json(refund))
+}
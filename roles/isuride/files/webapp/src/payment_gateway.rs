@@ -1,6 +1,9 @@
 use crate::models::Ride;
 use crate::Error;
 use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
 
 #[derive(Debug, thiserror::Error)]
 pub enum PaymentGatewayError {
@@ -13,6 +16,12 @@ pub enum PaymentGatewayError {
     },
     #[error("[GET /payments] unexpected status code ({0})")]
     GetPayment(reqwest::StatusCode),
+    #[error("[POST /payments] unexpected status code ({0})")]
+    PostPayment(reqwest::StatusCode),
+    #[error("[POST /refunds] unexpected status code ({0})")]
+    PostRefund(reqwest::StatusCode),
+    #[error("payment gateway circuit is open")]
+    CircuitOpen,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -20,12 +29,17 @@ pub struct PaymentGatewayPostPaymentRequest {
     pub amount: i32,
 }
 
+#[derive(Debug, serde::Serialize)]
+pub struct PaymentGatewayPostRefundRequest {
+    /// 返金元の請求を送ったときの Idempotency-Key
+    pub payment_idempotency_key: String,
+    pub amount: i32,
+}
+
 #[derive(Debug, serde::Deserialize)]
-struct PaymentGatewayGetPaymentsResponseOne {
-    #[allow(unused)]
-    amount: i32,
-    #[allow(unused)]
-    status: String,
+pub struct PaymentGatewayGetPaymentsResponseOne {
+    pub amount: i32,
+    pub status: String,
 }
 
 pub trait PostPaymentCallback<'a> {
@@ -44,52 +58,318 @@ where
     }
 }
 
-pub async fn request_payment_gateway_post_payment(
-    payment_gateway_url: &str,
-    token: &str,
-    param: &PaymentGatewayPostPaymentRequest,
-) -> Result<(), Error>
-{
-    // 失敗したらとりあえずリトライ
-    // FIXME: 社内決済マイクロサービスのインフラに異常が発生していて、同時にたくさんリクエストすると変なことになる可能性あり
-    let mut retry = 0;
-
-    let idempotency_key = ulid::Ulid::new().to_string();
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert(
-        "Idempotency-Key",
-        reqwest::header::HeaderValue::from_str(&idempotency_key).unwrap(),
-    );
-
-    loop {
-        let result = async {
-            let res = reqwest::Client::new()
-                .post(format!("{payment_gateway_url}/payments"))
-                .bearer_auth(token)
-                .headers(headers.clone())
-                .json(param)
-                .send()
-                .await
-                .map_err(PaymentGatewayError::Reqwest)?;
-
-            if res.status() != reqwest::StatusCode::NO_CONTENT {
-                return Err(PaymentGatewayError::GetPayment(res.status()).into());
+/// 1 回の請求で決済サービスに投げるリクエストの最大回数
+const MAX_ATTEMPTS: u32 = 6;
+const BACKOFF_BASE: Duration = Duration::from_millis(100);
+const BACKOFF_MAX: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// 決済サービスへ同時に投げるリクエストの上限
+const MAX_CONCURRENCY: usize = 16;
+/// 連続でこの回数失敗したらしばらく決済サービスへのリクエストを止める
+const CIRCUIT_FAILURE_THRESHOLD: u32 = 5;
+const CIRCUIT_OPEN_DURATION: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CircuitState {
+    Closed {
+        consecutive_failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// 様子見のリクエストを 1 つだけ通していて、その結果を待っている
+    HalfOpen {
+        probe_started_at: Instant,
+    },
+}
+
+#[derive(Debug)]
+struct CircuitBreaker {
+    state: CircuitState,
+}
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            state: CircuitState::Closed {
+                consecutive_failures: 0,
+            },
+        }
+    }
+}
+impl CircuitBreaker {
+    /// 開いている間は false を返す。期限が過ぎたら様子見のリクエストを 1 つだけ通し、
+    /// それが成功するまでは他のリクエストを即座に失敗させる
+    fn allows_request(&mut self, now: Instant) -> bool {
+        match self.state {
+            CircuitState::Closed { .. } => true,
+            CircuitState::Open { until } if now < until => false,
+            // 様子見のリクエストが結果を返さずに消えた (呼び出し側が諦めた) ときは、次のリクエストを様子見にする
+            CircuitState::HalfOpen { probe_started_at }
+                if now.duration_since(probe_started_at) < REQUEST_TIMEOUT =>
+            {
+                false
             }
-            Ok(())
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => {
+                self.state = CircuitState::HalfOpen {
+                    probe_started_at: now,
+                };
+                true
+            }
+        }
+    }
+
+    fn record_success(&mut self) {
+        if !matches!(self.state, CircuitState::Closed { .. }) {
+            tracing::info!("payment gateway circuit closed");
+        }
+        self.state = CircuitState::Closed {
+            consecutive_failures: 0,
+        };
+    }
+
+    fn record_failure(&mut self, now: Instant) {
+        let open = match &mut self.state {
+            CircuitState::Closed {
+                consecutive_failures,
+            } => {
+                *consecutive_failures += 1;
+                if *consecutive_failures >= CIRCUIT_FAILURE_THRESHOLD {
+                    tracing::warn!("payment gateway circuit opened");
+                    true
+                } else {
+                    false
+                }
+            }
+            // 様子見のリクエストが失敗したらまた開く
+            CircuitState::HalfOpen { .. } => true,
+            CircuitState::Open { .. } => true,
+        };
+        if open {
+            self.state = CircuitState::Open {
+                until: now + CIRCUIT_OPEN_DURATION,
+            };
+        }
+    }
+}
+
+/// 決済サービスへのリクエストの結果。リトライしてよいものとそうでないものを分ける
+enum Attempt {
+    Done(reqwest::Response),
+    Retryable(PaymentGatewayError),
+    Fatal(PaymentGatewayError),
+}
+
+/// 決済サービスのクライアント。
+/// コネクションプールを共有し、同時リクエスト数の制限、指数バックオフでのリトライ、
+/// 決済サービスが落ちている間に即座に失敗させるサーキットブレーカーを持つ。
+#[derive(Debug)]
+pub struct PaymentGatewayClient {
+    client: reqwest::Client,
+    semaphore: Semaphore,
+    circuit: Mutex<CircuitBreaker>,
+}
+impl Default for PaymentGatewayClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl PaymentGatewayClient {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("failed to build payment gateway client"),
+            semaphore: Semaphore::new(MAX_CONCURRENCY),
+            circuit: Mutex::new(CircuitBreaker::default()),
         }
-        .await;
-
-        if let Err(err) = result {
-            if retry < 5 {
-                retry += 1;
-                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-                continue;
-            } else {
+    }
+
+    pub async fn post_payment(
+        &self,
+        payment_gateway_url: &str,
+        token: &str,
+        idempotency_key: &str,
+        param: &PaymentGatewayPostPaymentRequest,
+    ) -> Result<(), Error> {
+        // 同じ Idempotency-Key で送る限り、リトライしても二重に請求されることはない
+        self.send_with_retry(
+            || {
+                self.client
+                    .post(format!("{payment_gateway_url}/payments"))
+                    .bearer_auth(token)
+                    .header("Idempotency-Key", idempotency_key)
+                    .json(param)
+            },
+            reqwest::StatusCode::NO_CONTENT,
+            PaymentGatewayError::PostPayment,
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn post_refund(
+        &self,
+        payment_gateway_url: &str,
+        token: &str,
+        idempotency_key: &str,
+        param: &PaymentGatewayPostRefundRequest,
+    ) -> Result<(), Error> {
+        self.send_with_retry(
+            || {
+                self.client
+                    .post(format!("{payment_gateway_url}/refunds"))
+                    .bearer_auth(token)
+                    .header("Idempotency-Key", idempotency_key)
+                    .json(param)
+            },
+            reqwest::StatusCode::NO_CONTENT,
+            PaymentGatewayError::PostRefund,
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_payments(
+        &self,
+        payment_gateway_url: &str,
+        token: &str,
+    ) -> Result<Vec<PaymentGatewayGetPaymentsResponseOne>, Error> {
+        let res = self
+            .send_with_retry(
+                || {
+                    self.client
+                        .get(format!("{payment_gateway_url}/payments"))
+                        .bearer_auth(token)
+                },
+                reqwest::StatusCode::OK,
+                PaymentGatewayError::GetPayment,
+            )
+            .await?;
+        let payments = res.json().await.map_err(PaymentGatewayError::Reqwest)?;
+        Ok(payments)
+    }
+
+    async fn send_with_retry(
+        &self,
+        build: impl Fn() -> reqwest::RequestBuilder,
+        expected: reqwest::StatusCode,
+        unexpected_status: fn(reqwest::StatusCode) -> PaymentGatewayError,
+    ) -> Result<reqwest::Response, PaymentGatewayError> {
+        let mut attempt = 0;
+        loop {
+            if !self.circuit.lock().unwrap().allows_request(Instant::now()) {
+                return Err(PaymentGatewayError::CircuitOpen);
+            }
+
+            let result = {
+                let _permit = self.semaphore.acquire().await.unwrap();
+                match build().send().await {
+                    Ok(res) if res.status() == expected => Attempt::Done(res),
+                    Ok(res) if is_retryable_status(res.status()) => {
+                        Attempt::Retryable(unexpected_status(res.status()))
+                    }
+                    Ok(res) => Attempt::Fatal(unexpected_status(res.status())),
+                    Err(e) => Attempt::Retryable(PaymentGatewayError::Reqwest(e)),
+                }
+            };
+
+            let err = match result {
+                Attempt::Done(res) => {
+                    self.circuit.lock().unwrap().record_success();
+                    return Ok(res);
+                }
+                Attempt::Fatal(err) => {
+                    // 4xx は決済サービス自体は動いているので、サーキットブレーカーの失敗には数えない
+                    self.circuit.lock().unwrap().record_success();
+                    return Err(err);
+                }
+                Attempt::Retryable(err) => err,
+            };
+            self.circuit.lock().unwrap().record_failure(Instant::now());
+
+            attempt += 1;
+            if attempt >= MAX_ATTEMPTS {
                 return Err(err);
             }
+            tracing::debug!(
+                attempt,
+                e = err.to_string(),
+                "retrying payment gateway request"
+            );
+            tokio::time::sleep(backoff(attempt)).await;
         }
-        break;
     }
+}
 
-    Ok(())
+fn is_retryable_status(status: reqwest::StatusCode) -> bool {
+    status.is_server_error()
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::REQUEST_TIMEOUT
+}
+
+/// `attempt` 回目の失敗の後に待つ時間。上限付きの指数バックオフに full jitter をかける
+fn backoff(attempt: u32) -> Duration {
+    use rand::Rng as _;
+    let cap = BACKOFF_BASE
+        .saturating_mul(1 << attempt.min(16))
+        .min(BACKOFF_MAX);
+    cap.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_circuit(now: Instant) -> CircuitBreaker {
+        let mut circuit = CircuitBreaker::default();
+        for _ in 0..CIRCUIT_FAILURE_THRESHOLD {
+            assert!(circuit.allows_request(now));
+            circuit.record_failure(now);
+        }
+        circuit
+    }
+
+    #[test]
+    fn circuit_opens_after_consecutive_failures() {
+        let now = Instant::now();
+        let mut circuit = open_circuit(now);
+        assert!(!circuit.allows_request(now));
+        assert!(!circuit.allows_request(now + CIRCUIT_OPEN_DURATION / 2));
+    }
+
+    #[test]
+    fn half_open_circuit_admits_a_single_probe() {
+        let now = Instant::now();
+        let mut circuit = open_circuit(now);
+        let later = now + CIRCUIT_OPEN_DURATION;
+        assert!(circuit.allows_request(later));
+        assert!(!circuit.allows_request(later));
+        assert!(!circuit.allows_request(later + Duration::from_millis(10)));
+
+        circuit.record_success();
+        assert!(circuit.allows_request(later));
+        assert!(circuit.allows_request(later));
+    }
+
+    #[test]
+    fn failed_probe_reopens_the_circuit() {
+        let now = Instant::now();
+        let mut circuit = open_circuit(now);
+        let later = now + CIRCUIT_OPEN_DURATION;
+        assert!(circuit.allows_request(later));
+        circuit.record_failure(later);
+        assert!(!circuit.allows_request(later + CIRCUIT_OPEN_DURATION / 2));
+        assert!(circuit.allows_request(later + CIRCUIT_OPEN_DURATION));
+    }
+
+    #[test]
+    fn abandoned_probe_is_replaced_after_the_request_timeout() {
+        let now = Instant::now();
+        let mut circuit = open_circuit(now);
+        let later = now + CIRCUIT_OPEN_DURATION;
+        assert!(circuit.allows_request(later));
+        assert!(!circuit.allows_request(later + REQUEST_TIMEOUT / 2));
+        assert!(circuit.allows_request(later + REQUEST_TIMEOUT));
+    }
 }
@@ -0,0 +1,585 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures::StreamExt as _;
+use tracing::{info, warn};
+
+use crate::models::{Payment, PaymentStatus, PaymentToken, Ride};
+use crate::payment_gateway::{
+    PaymentGatewayError, PaymentGatewayPostPaymentRequest, PostPaymentCallback,
+};
+use crate::{AppState, Error};
+
+/// 一度に決済サービスへ投げる請求の数
+const PAYMENT_CONCURRENCY: usize = 8;
+/// 一度に引き取る請求の数
+const PAYMENT_BATCH_SIZE: u32 = 100;
+/// 通知を取りこぼしても PENDING の請求を拾えるようにするための間隔
+const PAYMENT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// 引き取った請求をこの時間内に片付けられなければ、ワーカーが落ちたとみなして他のワーカーが引き取る
+const PAYMENT_LEASE: Duration = Duration::from_secs(30);
+/// 失敗した請求をやり直す回数の上限。超えたら FAILED にする
+const MAX_PAYMENT_ATTEMPTS: i32 = 5;
+const MAX_PAYMENT_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
+/// ライドの完了と同じトランザクションで決済の請求を積んでおく。
+/// 実際の請求は `run_payment_worker` がトランザクションの外で行う。
+pub async fn enqueue_payment(
+    tx: &mut sqlx::MySqlConnection,
+    ride_id: &str,
+    user_id: &str,
+    amount: i32,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "INSERT INTO payments (id, ride_id, user_id, amount, idempotency_key) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(ulid::Ulid::new().to_string())
+    .bind(ride_id)
+    .bind(user_id)
+    .bind(amount)
+    .bind(ulid::Ulid::new().to_string())
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
+pub async fn run_payment_worker(state: AppState) {
+    loop {
+        tokio::select! {
+            _ = state.payment_notify.notified() => {}
+            _ = tokio::time::sleep(PAYMENT_POLL_INTERVAL) => {}
+        }
+        if let Err(e) = process_pending_payments(&state).await {
+            warn!(e = e.to_string(), "failed to process pending payments");
+        }
+    }
+}
+
+/// 請求できる PENDING の請求と、リースが切れたまま処理中の請求をまとめて引き取る。
+/// 1 つの UPDATE で PROCESSING にするので、複数のワーカーやインスタンスが同じ請求を引き取ることはない
+async fn claim_payments(pool: &sqlx::MySqlPool) -> sqlx::Result<(String, Vec<Payment>)> {
+    let worker_id = ulid::Ulid::new().to_string();
+    sqlx::query(
+        "UPDATE payments SET status = 'PROCESSING', claimed_by = ?, locked_until = DATE_ADD(NOW(6), INTERVAL ? SECOND) WHERE (status = 'PENDING' AND next_attempt_at <= NOW(6)) OR (status = 'PROCESSING' AND locked_until < NOW(6)) ORDER BY next_attempt_at LIMIT ?",
+    )
+    .bind(&worker_id)
+    .bind(PAYMENT_LEASE.as_secs())
+    .bind(PAYMENT_BATCH_SIZE)
+    .execute(pool)
+    .await?;
+    let payments = sqlx::query_as(
+        "SELECT * FROM payments WHERE status = 'PROCESSING' AND claimed_by = ? ORDER BY created_at",
+    )
+    .bind(&worker_id)
+    .fetch_all(pool)
+    .await?;
+    Ok((worker_id, payments))
+}
+
+/// `attempts` 回失敗した請求をやり直すまでの時間
+fn payment_retry_backoff(attempts: i32) -> Duration {
+    let exponent = u32::try_from(attempts.clamp(0, 16)).unwrap();
+    (Duration::from_secs(1) * 2u32.pow(exponent)).min(MAX_PAYMENT_RETRY_BACKOFF)
+}
+
+async fn process_pending_payments(state: &AppState) -> Result<(), Error> {
+    let (worker_id, payments) = claim_payments(&state.pool).await?;
+    if payments.is_empty() {
+        return Ok(());
+    }
+
+    let payment_gateway_url: String =
+        sqlx::query_scalar("SELECT value FROM settings WHERE name = 'payment_gateway_url'")
+            .fetch_one(&state.pool)
+            .await?;
+
+    futures::stream::iter(payments)
+        .for_each_concurrent(PAYMENT_CONCURRENCY, |payment| {
+            let payment_gateway_url = &payment_gateway_url;
+            let worker_id = &worker_id;
+            async move {
+                if let Err(e) =
+                    process_payment(state, payment_gateway_url, worker_id, &payment).await
+                {
+                    warn!(
+                        payment_id = payment.id,
+                        e = e.to_string(),
+                        "failed to process payment"
+                    );
+                }
+            }
+        })
+        .await;
+
+    Ok(())
+}
+
+/// デフォルトの支払い方法を先頭に、登録順に並べた支払い方法
+pub async fn retrieve_payment_tokens<'e, E>(
+    executor: E,
+    user_id: &str,
+) -> sqlx::Result<Vec<PaymentToken>>
+where
+    E: 'e + sqlx::Executor<'e, Database = sqlx::MySql>,
+{
+    sqlx::query_as(
+        "SELECT * FROM payment_tokens WHERE user_id = ? ORDER BY is_default DESC, created_at, id",
+    )
+    .bind(user_id)
+    .fetch_all(executor)
+    .await
+}
+
+/// 決済サービスが請求を断ったかどうか。断られたときだけ次の支払い方法を試してよい
+pub(crate) fn is_declined(e: &Error) -> bool {
+    matches!(
+        e,
+        Error::PaymentGateway(PaymentGatewayError::PostPayment(status)) if status.is_client_error()
+    )
+}
+
+/// 支払い方法ごとの Idempotency-Key。断られた請求を別の支払い方法で送り直すときに、
+/// 決済サービスが同じキーの結果 (断った結果) を返したり、キーの使い回しを拒んだりしないようにする
+fn payment_idempotency_key(payment: &Payment, payment_token: &PaymentToken) -> String {
+    format!("{}-{}", payment.idempotency_key, payment_token.id)
+}
+
+impl Payment {
+    /// 請求に成功したときに送った Idempotency-Key。返金で元の請求を指すのに使う
+    pub fn charged_idempotency_key(&self) -> &str {
+        self.charged_idempotency_key
+            .as_deref()
+            .unwrap_or(&self.idempotency_key)
+    }
+}
+
+async fn process_payment(
+    state: &AppState,
+    payment_gateway_url: &str,
+    worker_id: &str,
+    payment: &Payment,
+) -> Result<(), Error> {
+    // 突き合わせで積み直した請求は、前に成功した支払い方法と Idempotency-Key のまま送る。
+    // 決済サービスが実は記録していれば同じキーの結果を返すだけで、二重には請求されない
+    let payment_tokens = match (&payment.payment_token_id, &payment.charged_idempotency_key) {
+        (Some(payment_token_id), Some(_)) => {
+            let payment_token: Option<PaymentToken> =
+                sqlx::query_as("SELECT * FROM payment_tokens WHERE id = ?")
+                    .bind(payment_token_id)
+                    .fetch_optional(&state.pool)
+                    .await?;
+            payment_token.into_iter().collect()
+        }
+        _ => retrieve_payment_tokens(&state.pool, &payment.user_id).await?,
+    };
+
+    let mut result = Err(Error::BadRequest("payment token not registered"));
+    for payment_token in &payment_tokens {
+        let idempotency_key = match &payment.charged_idempotency_key {
+            Some(idempotency_key) => idempotency_key.clone(),
+            None => payment_idempotency_key(payment, payment_token),
+        };
+        result = state
+            .payment_gateway
+            .post_payment(
+                payment_gateway_url,
+                &payment_token.token,
+                &idempotency_key,
+                &PaymentGatewayPostPaymentRequest {
+                    amount: payment.amount,
+                },
+            )
+            .await
+            .map(|()| (payment_token.id.as_str(), idempotency_key));
+        // 5xx や通信エラーでは請求が通っているかもしれないので、別の支払い方法では請求しない
+        match &result {
+            Err(e) if is_declined(e) => {
+                info!(
+                    payment_id = payment.id,
+                    payment_token_id = payment_token.id,
+                    e = e.to_string(),
+                    "payment declined, trying next payment method"
+                );
+            }
+            _ => break,
+        }
+    }
+
+    // 引き取ったワーカーのままのときだけ結果を書く。リースが切れて他のワーカーに移っていたらそちらに任せる
+    match result {
+        // 決済サービスが落ちている間は請求した回数に数えずに PENDING に戻し、回復してから請求する
+        Err(Error::PaymentGateway(PaymentGatewayError::CircuitOpen)) => {
+            sqlx::query("UPDATE payments SET status = 'PENDING', claimed_by = NULL, locked_until = NULL WHERE id = ? AND claimed_by = ?")
+                .bind(&payment.id)
+                .bind(worker_id)
+                .execute(&state.pool)
+                .await?;
+        }
+        Ok((payment_token_id, idempotency_key)) => {
+            sqlx::query("UPDATE payments SET status = 'SUCCEEDED', payment_token_id = ?, charged_idempotency_key = ?, attempts = attempts + 1, last_error = NULL, claimed_by = NULL, locked_until = NULL WHERE id = ? AND claimed_by = ?")
+                .bind(payment_token_id)
+                .bind(idempotency_key)
+                .bind(&payment.id)
+                .bind(worker_id)
+                .execute(&state.pool)
+                .await?;
+            info!(
+                payment_id = payment.id,
+                ride_id = payment.ride_id,
+                "payment succeeded"
+            );
+        }
+        // 同じ Idempotency-Key で送り直すので、やり直しても二重に請求されることはない
+        Err(e) if payment.attempts + 1 < MAX_PAYMENT_ATTEMPTS => {
+            let backoff = payment_retry_backoff(payment.attempts);
+            sqlx::query("UPDATE payments SET status = 'PENDING', attempts = attempts + 1, last_error = ?, next_attempt_at = DATE_ADD(NOW(6), INTERVAL ? SECOND), claimed_by = NULL, locked_until = NULL WHERE id = ? AND claimed_by = ?")
+                .bind(e.to_string())
+                .bind(backoff.as_secs())
+                .bind(&payment.id)
+                .bind(worker_id)
+                .execute(&state.pool)
+                .await?;
+            info!(
+                payment_id = payment.id,
+                ride_id = payment.ride_id,
+                attempts = payment.attempts + 1,
+                backoff_secs = backoff.as_secs(),
+                e = e.to_string(),
+                "payment failed, retrying later"
+            );
+        }
+        Err(e) => {
+            sqlx::query("UPDATE payments SET status = 'FAILED', attempts = attempts + 1, last_error = ?, claimed_by = NULL, locked_until = NULL WHERE id = ? AND claimed_by = ?")
+                .bind(e.to_string())
+                .bind(&payment.id)
+                .bind(worker_id)
+                .execute(&state.pool)
+                .await?;
+            warn!(
+                payment_id = payment.id,
+                ride_id = payment.ride_id,
+                e = e.to_string(),
+                "payment failed"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct MissingCharge {
+    pub ride_id: String,
+    pub amount: i32,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct DuplicateCharge {
+    pub amount: i32,
+    pub count: usize,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ReconciliationReport {
+    pub user_id: String,
+    pub ride_count: usize,
+    pub payment_count: usize,
+    /// 請求に成功したと記録しているのに決済サービスに記録が無い請求
+    pub missing: Vec<MissingCharge>,
+    /// やり直しても請求できなかった請求。請求し直さずに報告だけする
+    pub failed: Vec<MissingCharge>,
+    /// 請求に使った支払い方法が削除されていて、決済サービスで確かめられない請求。請求し直さずに報告だけする
+    pub unverifiable: Vec<MissingCharge>,
+    /// 完了したのに請求の記録が無いライド。請求し直さずに報告だけする
+    pub unrecorded: Vec<String>,
+    /// どのライドとも対応しない余分な請求
+    pub duplicates: Vec<DuplicateCharge>,
+    /// 請求し直したライドの ID
+    pub reposted: Vec<String>,
+}
+
+/// 完了済みのライドを作成順に返す。決済サービスの GET /payments と突き合わせるのに使う
+pub async fn retrieve_completed_rides(
+    tx: &mut sqlx::MySqlConnection,
+    user_id: &str,
+) -> Result<Vec<Ride>, Error> {
+    let rides = sqlx::query_as(
+        "SELECT rides.* FROM rides INNER JOIN ride_statuses ON ride_statuses.ride_id = rides.id WHERE rides.user_id = ? AND ride_statuses.status = 'COMPLETED' ORDER BY rides.created_at",
+    )
+    .bind(user_id)
+    .fetch_all(&mut *tx)
+    .await?;
+    Ok(rides)
+}
+
+/// 決済サービス上の請求を、支払い方法ごとに金額別の数で数えたもの
+#[derive(Debug, Default)]
+struct GatewayCharges(HashMap<String, HashMap<i32, usize>>);
+
+impl GatewayCharges {
+    fn add(&mut self, payment_token_id: &str, amount: i32) {
+        *self
+            .0
+            .entry(payment_token_id.to_owned())
+            .or_default()
+            .entry(amount)
+            .or_default() += 1;
+    }
+
+    /// 指定した支払い方法の同じ金額の請求を一つ対応済みにする
+    fn take(&mut self, payment_token_id: &str, amount: i32) -> bool {
+        match self
+            .0
+            .get_mut(payment_token_id)
+            .and_then(|counts| counts.get_mut(&amount))
+        {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// まだどの支払い方法に請求したか分からない請求のために、どれかの支払い方法の請求を対応済みにする
+    fn take_any(&mut self, amount: i32) -> bool {
+        let payment_token_ids: Vec<String> = self.0.keys().cloned().collect();
+        payment_token_ids
+            .iter()
+            .any(|payment_token_id| self.take(payment_token_id, amount))
+    }
+
+    /// どの請求とも対応しなかった請求
+    fn into_duplicates(self) -> Vec<DuplicateCharge> {
+        let mut count_by_amount: HashMap<i32, usize> = HashMap::new();
+        for (amount, count) in self.0.into_values().flatten() {
+            *count_by_amount.entry(amount).or_default() += count;
+        }
+        let mut duplicates: Vec<DuplicateCharge> = count_by_amount
+            .into_iter()
+            .filter(|&(_, count)| count > 0)
+            .map(|(amount, count)| DuplicateCharge { amount, count })
+            .collect();
+        duplicates.sort_by_key(|duplicate| duplicate.amount);
+        duplicates
+    }
+}
+
+/// ユーザーの完了済みライドの請求と決済サービス上の請求を突き合わせる。
+/// 決済サービスは請求の金額と状態しか返さないので、請求に使った支払い方法ごとに、同じ金額の請求の数で比べる。
+/// 支払い方法が削除された請求は決済サービスに問い合わせられないので、確かめられないものとして報告だけする。
+///
+/// 先に DB の請求を読んでから決済サービスの請求を取るので、その間に決済ワーカーが片付けた請求は
+/// 決済サービス側にだけ現れる (余分な請求として報告されるが、請求し直すことはない)。
+/// `repost` が真なら、成功したと記録しているのに決済サービスに無い請求だけを、
+/// 前に成功した支払い方法と Idempotency-Key のまま決済ワーカーに積み直す。
+/// 決済サービスが実は記録していれば、同じキーの請求として二重には請求されない。
+pub async fn reconcile_user_payments<F>(
+    state: &AppState,
+    user_id: &str,
+    retrieve_rides: F,
+    repost: bool,
+) -> Result<ReconciliationReport, Error>
+where
+    F: for<'a> PostPaymentCallback<'a>,
+{
+    let mut conn = state.pool.acquire().await?;
+
+    let payment_tokens = retrieve_payment_tokens(&mut *conn, user_id).await?;
+    if payment_tokens.is_empty() {
+        return Err(Error::BadRequest("payment token not registered"));
+    }
+    let payment_gateway_url: String =
+        sqlx::query_scalar("SELECT value FROM settings WHERE name = 'payment_gateway_url'")
+            .fetch_one(&mut *conn)
+            .await?;
+
+    let rides = retrieve_rides.call(&mut conn, user_id).await?;
+    let mut payment_by_ride_id: HashMap<String, Payment> =
+        sqlx::query_as("SELECT * FROM payments WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_all(&mut *conn)
+            .await?
+            .into_iter()
+            .map(|payment: Payment| (payment.ride_id.clone(), payment))
+            .collect();
+
+    let mut charges = GatewayCharges::default();
+    let mut payment_count = 0;
+    for payment_token in &payment_tokens {
+        let gateway_payments = state
+            .payment_gateway
+            .get_payments(&payment_gateway_url, &payment_token.token)
+            .await?;
+        payment_count += gateway_payments.len();
+        for payment in gateway_payments {
+            charges.add(&payment_token.id, payment.amount);
+        }
+    }
+
+    let mut missing = Vec::new();
+    let mut failed = Vec::new();
+    let mut unverifiable = Vec::new();
+    let mut unrecorded = Vec::new();
+    let mut missing_payments = Vec::new();
+    let mut in_flight_amounts = Vec::new();
+    for ride in &rides {
+        let Some(payment) = payment_by_ride_id.remove(&ride.id) else {
+            unrecorded.push(ride.id.clone());
+            continue;
+        };
+        let charge = MissingCharge {
+            ride_id: ride.id.clone(),
+            amount: payment.amount,
+        };
+        match payment.status {
+            // 決済ワーカーが処理中の請求は、成功した請求と対応させた残りと対応させるだけにする
+            PaymentStatus::Pending | PaymentStatus::Processing => {
+                in_flight_amounts.push(payment.amount);
+            }
+            PaymentStatus::Failed => failed.push(charge),
+            PaymentStatus::Succeeded => match &payment.payment_token_id {
+                Some(payment_token_id)
+                    if payment_tokens
+                        .iter()
+                        .any(|token| &token.id == payment_token_id) =>
+                {
+                    if !charges.take(payment_token_id, payment.amount) {
+                        missing.push(charge);
+                        missing_payments.push(payment);
+                    }
+                }
+                _ => unverifiable.push(charge),
+            },
+        }
+    }
+    for amount in in_flight_amounts {
+        charges.take_any(amount);
+    }
+    let duplicates = charges.into_duplicates();
+
+    if rides.len() != payment_count {
+        let e = PaymentGatewayError::UnexpectedNumberOfPayments {
+            ride_count: rides.len(),
+            payment_count,
+        };
+        warn!(user_id, e = e.to_string(), "payments do not match rides");
+    }
+
+    let mut reposted = Vec::new();
+    if repost {
+        for payment in missing_payments {
+            // 読んでから変わった請求 (返金や他の突き合わせで触られたもの) には手を出さない。
+            // 支払い方法と Idempotency-Key は残し、決済ワーカーが同じ請求として送り直す
+            let result = sqlx::query(
+                "UPDATE payments SET status = 'PENDING', attempts = 0, next_attempt_at = NOW(6) WHERE id = ? AND status = 'SUCCEEDED' AND updated_at = ? AND EXISTS (SELECT 1 FROM payment_tokens WHERE id = payments.payment_token_id)",
+            )
+            .bind(&payment.id)
+            .bind(payment.updated_at)
+            .execute(&mut *conn)
+            .await?;
+            if result.rows_affected() > 0 {
+                reposted.push(payment.ride_id);
+            }
+        }
+        if !reposted.is_empty() {
+            state.payment_notify.notify_one();
+        }
+    }
+
+    Ok(ReconciliationReport {
+        user_id: user_id.to_owned(),
+        ride_count: rides.len(),
+        payment_count,
+        missing,
+        failed,
+        unverifiable,
+        unrecorded,
+        duplicates,
+        reposted,
+    })
+}
+
+/// 決済の記録があるユーザー全員について突き合わせる
+pub async fn reconcile_all_payments(
+    state: &AppState,
+    repost: bool,
+) -> Result<Vec<ReconciliationReport>, Error> {
+    let user_ids: Vec<String> = sqlx::query_scalar("SELECT DISTINCT user_id FROM payments")
+        .fetch_all(&state.pool)
+        .await?;
+
+    let mut reports = Vec::with_capacity(user_ids.len());
+    for user_id in user_ids {
+        match reconcile_user_payments(state, &user_id, retrieve_completed_rides, repost).await {
+            Ok(report) => reports.push(report),
+            Err(e) => warn!(user_id, e = e.to_string(), "failed to reconcile payments"),
+        }
+    }
+    Ok(reports)
+}
+
+/// 定期的に突き合わせて食い違いを報告する。`repost` が偽なら請求し直さない
+pub async fn run_payment_reconciler(state: AppState, interval: Duration, repost: bool) {
+    loop {
+        tokio::time::sleep(interval).await;
+        match reconcile_all_payments(&state, repost).await {
+            Ok(reports) => {
+                for report in reports {
+                    if !report.missing.is_empty()
+                        || !report.failed.is_empty()
+                        || !report.unverifiable.is_empty()
+                        || !report.unrecorded.is_empty()
+                        || !report.duplicates.is_empty()
+                    {
+                        warn!(
+                            user_id = report.user_id,
+                            missing = report.missing.len(),
+                            failed = report.failed.len(),
+                            unverifiable = report.unverifiable.len(),
+                            unrecorded = report.unrecorded.len(),
+                            duplicates = report.duplicates.len(),
+                            reposted = report.reposted.len(),
+                            "payment mismatch found"
+                        );
+                    }
+                }
+            }
+            Err(e) => warn!(e = e.to_string(), "failed to reconcile payments"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn charges_are_matched_on_the_payment_method_that_was_charged() {
+        let mut charges = GatewayCharges::default();
+        charges.add("token-a", 1000);
+        charges.add("token-b", 1000);
+
+        assert!(charges.take("token-a", 1000));
+        // 別の支払い方法の同じ金額の請求とは対応させない
+        assert!(!charges.take("token-a", 1000));
+        assert!(!charges.take("token-c", 1000));
+        assert!(charges.take_any(1000));
+        assert!(!charges.take_any(1000));
+        assert!(charges.into_duplicates().is_empty());
+    }
+
+    #[test]
+    fn unmatched_charges_are_reported_as_duplicates_by_amount() {
+        let mut charges = GatewayCharges::default();
+        charges.add("token-a", 1000);
+        charges.add("token-b", 1000);
+        charges.add("token-b", 500);
+        assert!(charges.take("token-b", 500));
+
+        let duplicates = charges.into_duplicates();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].amount, 1000);
+        assert_eq!(duplicates[0].count, 2);
+    }
+}
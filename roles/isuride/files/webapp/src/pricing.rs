@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+
+use crate::chair_registry::ChairRegistry;
+use crate::Coordinate;
+
+/// 運賃の計算方法。`settings` テーブルの `pricing` に JSON で置く。
+/// 設定が無い項目は以前の固定の運賃 (初乗り 500、距離 1 あたり 100) と同じになる
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct PricingEngine {
+    pub base_fare: i32,
+    pub fare_per_distance: i32,
+    /// 割引した後もこれより安くはしない
+    pub minimum_fare: i32,
+    /// 椅子のモデル (`chair_models.name`) ごとの距離運賃の倍率。無いモデルは 1 倍。
+    /// ユーザーに請求する運賃は椅子が決まる前に見積もりで固定するので掛けず、
+    /// 椅子が決まってから計算するオーナーの売上 (`sale`) にだけ掛ける
+    pub model_multipliers: HashMap<String, f64>,
+    pub surge: SurgeConfig,
+}
+
+/// 需要と供給に応じて距離運賃に掛ける倍率 (サージ) の決め方。
+/// 乗車位置の周りで椅子を待っているライドが空いている椅子より多いほど高くする
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SurgeConfig {
+    /// 需要と供給を数える、乗車位置からのマンハッタン距離
+    pub radius: i32,
+    /// 待っているライドが空いている椅子を 1 つ上回るごとに上げる倍率
+    pub step: f64,
+    /// 1 ならサージを掛けない。設定しなければ 1 なので、サージを使うときは明示的に上げる
+    pub max_multiplier: f64,
+}
+impl Default for SurgeConfig {
+    fn default() -> Self {
+        Self {
+            radius: 50,
+            step: 0.1,
+            max_multiplier: 1.0,
+        }
+    }
+}
+impl SurgeConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.max_multiplier > 1.0
+    }
+
+    pub fn multiplier(&self, waiting_rides: usize, free_chairs: usize) -> f64 {
+        let shortage = waiting_rides.saturating_sub(free_chairs) as f64;
+        let multiplier = (1.0 + self.step * shortage).clamp(1.0, self.max_multiplier.max(1.0));
+        // レスポンスに載せるので小数点以下 2 桁に丸める
+        (multiplier * 100.0).round() / 100.0
+    }
+}
+impl Default for PricingEngine {
+    fn default() -> Self {
+        Self {
+            base_fare: 500,
+            fare_per_distance: 100,
+            minimum_fare: 0,
+            model_multipliers: HashMap::new(),
+            surge: SurgeConfig::default(),
+        }
+    }
+}
+impl PricingEngine {
+    pub async fn load(tx: &mut sqlx::MySqlConnection) -> sqlx::Result<Self> {
+        let value: Option<String> =
+            sqlx::query_scalar("SELECT value FROM settings WHERE name = 'pricing'")
+                .fetch_optional(tx)
+                .await?;
+        let Some(value) = value else {
+            return Ok(Self::default());
+        };
+        serde_json::from_str(&value).map_err(|e| sqlx::Error::Decode(Box::new(e)))
+    }
+
+    /// `center` の周りの今のサージの倍率。ライドを作るときにライドに固定する
+    pub async fn surge_multiplier(
+        &self,
+        tx: &mut sqlx::MySqlConnection,
+        chair_registry: &ChairRegistry,
+        center: Coordinate,
+    ) -> sqlx::Result<f64> {
+        if !self.surge.is_enabled() {
+            return Ok(1.0);
+        }
+        // 乗車位置の範囲で rides_chair_id_pickup を絞ってから距離とキャンセルを確かめる
+        let radius = self.surge.radius;
+        let waiting_rides: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM rides WHERE chair_id IS NULL AND pickup_latitude BETWEEN ? AND ? AND pickup_longitude BETWEEN ? AND ? AND ABS(pickup_latitude - ?) + ABS(pickup_longitude - ?) <= ? AND NOT EXISTS (SELECT 1 FROM ride_statuses WHERE ride_id = rides.id AND status = 'CANCELED')",
+        )
+        .bind(center.latitude - radius)
+        .bind(center.latitude + radius)
+        .bind(center.longitude - radius)
+        .bind(center.longitude + radius)
+        .bind(center.latitude)
+        .bind(center.longitude)
+        .bind(radius)
+        .fetch_one(tx)
+        .await?;
+        let free_chairs = chair_registry
+            .nearby_free_chairs(center, self.surge.radius, None)
+            .len();
+        Ok(self.surge.multiplier(waiting_rides as usize, free_chairs))
+    }
+
+    /// 割引前の運賃。椅子が決まっていないときは `chair_model` に None を渡す
+    pub fn fare(
+        &self,
+        chair_model: Option<&str>,
+        surge_multiplier: f64,
+        pickup: Coordinate,
+        destination: Coordinate,
+    ) -> i32 {
+        self.discounted_fare(chair_model, surge_multiplier, pickup, destination, 0)
+    }
+
+    /// 初乗り運賃を除いた、距離に応じた運賃。クーポンの割引はここから引く
+    pub fn metered_fare(
+        &self,
+        chair_model: Option<&str>,
+        surge_multiplier: f64,
+        pickup: Coordinate,
+        destination: Coordinate,
+    ) -> i32 {
+        let distance = crate::calculate_distance(
+            pickup.latitude,
+            pickup.longitude,
+            destination.latitude,
+            destination.longitude,
+        );
+        let multiplier = chair_model
+            .and_then(|model| self.model_multipliers.get(model))
+            .copied()
+            .unwrap_or(1.0);
+        (f64::from(self.fare_per_distance * distance) * multiplier * surge_multiplier).round()
+            as i32
+    }
+
+    /// オーナーの売上になる割引前の運賃と、そのうちサージの分。
+    /// ライドが完了したときに担当した椅子のモデルで計算して、ライドに固定する
+    pub fn sale(
+        &self,
+        chair_model: &str,
+        surge_multiplier: f64,
+        pickup: Coordinate,
+        destination: Coordinate,
+    ) -> (i32, i32) {
+        let chair_model = Some(chair_model);
+        (
+            self.fare(chair_model, surge_multiplier, pickup, destination),
+            self.surge_fare(chair_model, surge_multiplier, pickup, destination, 0),
+        )
+    }
+
+    /// `discounted_fare` のうちサージで上がった分
+    pub fn surge_fare(
+        &self,
+        chair_model: Option<&str>,
+        surge_multiplier: f64,
+        pickup: Coordinate,
+        destination: Coordinate,
+        discount: i32,
+    ) -> i32 {
+        self.discounted_fare(chair_model, surge_multiplier, pickup, destination, discount)
+            - self.discounted_fare(chair_model, 1.0, pickup, destination, discount)
+    }
+
+    /// クーポンの割引は距離運賃から引き、初乗り運賃は割り引かない
+    pub fn discounted_fare(
+        &self,
+        chair_model: Option<&str>,
+        surge_multiplier: f64,
+        pickup: Coordinate,
+        destination: Coordinate,
+        discount: i32,
+    ) -> i32 {
+        let metered_fare = self.metered_fare(chair_model, surge_multiplier, pickup, destination);
+        let discounted_metered_fare = std::cmp::max(metered_fare - discount, 0);
+        std::cmp::max(self.base_fare + discounted_metered_fare, self.minimum_fare)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PICKUP: Coordinate = Coordinate {
+        latitude: 0,
+        longitude: 0,
+    };
+    /// 乗車位置から距離 7
+    const DESTINATION: Coordinate = Coordinate {
+        latitude: 3,
+        longitude: -4,
+    };
+
+    #[test]
+    fn default_pricing_is_the_fixed_fare() {
+        let pricing = PricingEngine::default();
+        assert_eq!(pricing.fare(None, 1.0, PICKUP, DESTINATION), 500 + 100 * 7);
+        assert_eq!(
+            pricing.fare(Some("unknown"), 1.0, PICKUP, DESTINATION),
+            1200
+        );
+    }
+
+    #[test]
+    fn model_multiplier_applies_to_the_metered_fare_only() {
+        let pricing = PricingEngine {
+            model_multipliers: HashMap::from([("fast".to_owned(), 1.5)]),
+            ..Default::default()
+        };
+        assert_eq!(
+            pricing.fare(Some("fast"), 1.0, PICKUP, DESTINATION),
+            500 + 1050
+        );
+        assert_eq!(pricing.fare(Some("slow"), 1.0, PICKUP, DESTINATION), 1200);
+        assert_eq!(pricing.fare(None, 1.0, PICKUP, DESTINATION), 1200);
+        assert_eq!(
+            pricing.fare(Some("fast"), 2.0, PICKUP, DESTINATION),
+            500 + 2100
+        );
+    }
+
+    #[test]
+    fn metered_fare_is_rounded_to_the_nearest_integer() {
+        let pricing = PricingEngine::default();
+        // 700 * 1.337 = 935.9
+        assert_eq!(pricing.fare(None, 1.337, PICKUP, DESTINATION), 500 + 936);
+        // 700 * 1.0005 = 700.35
+        assert_eq!(pricing.fare(None, 1.0005, PICKUP, DESTINATION), 500 + 700);
+    }
+
+    #[test]
+    fn metered_fare_excludes_the_base_fare() {
+        let pricing = PricingEngine::default();
+        assert_eq!(pricing.metered_fare(None, 1.5, PICKUP, DESTINATION), 1050);
+        assert_eq!(
+            pricing.fare(None, 1.5, PICKUP, DESTINATION),
+            pricing.base_fare + pricing.metered_fare(None, 1.5, PICKUP, DESTINATION)
+        );
+    }
+
+    #[test]
+    fn discount_never_reduces_the_base_fare() {
+        let pricing = PricingEngine::default();
+        assert_eq!(
+            pricing.discounted_fare(None, 1.0, PICKUP, DESTINATION, 300),
+            500 + 400
+        );
+        assert_eq!(
+            pricing.discounted_fare(None, 1.0, PICKUP, DESTINATION, 10_000),
+            500
+        );
+    }
+
+    #[test]
+    fn discounted_fare_is_at_least_minimum_fare() {
+        let pricing = PricingEngine {
+            minimum_fare: 1000,
+            ..Default::default()
+        };
+        assert_eq!(
+            pricing.discounted_fare(None, 1.0, PICKUP, DESTINATION, 700),
+            1000
+        );
+        assert_eq!(
+            pricing.discounted_fare(None, 1.0, PICKUP, DESTINATION, 0),
+            1200
+        );
+    }
+
+    #[test]
+    fn surge_is_disabled_unless_configured() {
+        let surge = SurgeConfig::default();
+        assert!(!surge.is_enabled());
+        assert_eq!(surge.multiplier(100, 0), 1.0);
+
+        let surge = SurgeConfig {
+            max_multiplier: 2.0,
+            ..Default::default()
+        };
+        assert!(surge.is_enabled());
+        assert_eq!(surge.multiplier(3, 5), 1.0);
+        assert_eq!(surge.multiplier(8, 5), 1.3);
+        assert_eq!(surge.multiplier(100, 0), 2.0);
+    }
+
+    #[test]
+    fn surge_fare_is_the_part_added_by_the_multiplier() {
+        let pricing = PricingEngine::default();
+        assert_eq!(pricing.surge_fare(None, 1.0, PICKUP, DESTINATION, 0), 0);
+        assert_eq!(pricing.surge_fare(None, 1.5, PICKUP, DESTINATION, 0), 350);
+        assert_eq!(pricing.surge_fare(None, 1.5, PICKUP, DESTINATION, 300), 350);
+    }
+
+    #[test]
+    fn sale_applies_the_chair_model_multiplier_and_ignores_discounts() {
+        let pricing = PricingEngine {
+            model_multipliers: HashMap::from([("fast".to_owned(), 1.5)]),
+            surge: SurgeConfig {
+                max_multiplier: 2.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(pricing.sale("slow", 1.0, PICKUP, DESTINATION), (1200, 0));
+        assert_eq!(pricing.sale("fast", 1.0, PICKUP, DESTINATION), (1550, 0));
+        assert_eq!(pricing.sale("fast", 2.0, PICKUP, DESTINATION), (2600, 1050));
+    }
+}
@@ -0,0 +1,189 @@
+use std::time::Duration;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use hmac::{Hmac, Mac as _};
+use sha2::Sha256;
+
+use crate::{Coordinate, Error};
+
+/// 見積もりからライドを作るまでに許す時間
+const QUOTE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// 見積もった運賃と、その運賃を出すのに使った条件。
+/// 署名してクライアントに渡すので、ライドを作るときに DB に問い合わせずに検証できる
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FareQuote {
+    /// 見積もりごとに振る ID。ライドに記録して、同じ見積もりでライドを 2 回作れないようにする
+    pub id: String,
+    pub user_id: String,
+    pub pickup: Coordinate,
+    pub destination: Coordinate,
+    pub fare: i32,
+    pub surge_multiplier: f64,
+    /// 見積もりで使ったクーポン。ライドを作るときもこのクーポンを使う
+    pub coupon_code: Option<String>,
+    pub expires_at: i64,
+}
+impl FareQuote {
+    pub fn new(
+        user_id: String,
+        pickup: Coordinate,
+        destination: Coordinate,
+        fare: i32,
+        surge_multiplier: f64,
+        coupon_code: Option<String>,
+    ) -> Self {
+        Self {
+            id: ulid::Ulid::new().to_string(),
+            user_id,
+            pickup,
+            destination,
+            fare,
+            surge_multiplier,
+            coupon_code,
+            expires_at: (chrono::Utc::now() + QUOTE_TTL).timestamp_millis(),
+        }
+    }
+}
+
+/// 見積もりの ID は `base64(JSON).base64(HMAC-SHA256)`
+#[derive(Debug)]
+pub struct QuoteSigner {
+    key: Vec<u8>,
+}
+impl QuoteSigner {
+    /// 複数のインスタンスで動かすときは `ISUCON_QUOTE_SECRET` で鍵を揃える。
+    /// 無ければ起動ごとに鍵を作るので、再起動すると発行済みの見積もりは使えなくなる
+    pub fn from_env() -> Self {
+        let key = match std::env::var("ISUCON_QUOTE_SECRET") {
+            Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+            _ => crate::secure_random_str(32).into_bytes(),
+        };
+        Self { key }
+    }
+
+    pub fn sign(&self, quote: &FareQuote) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(quote).unwrap());
+        let signature = URL_SAFE_NO_PAD.encode(self.mac(&payload).finalize().into_bytes());
+        format!("{payload}.{signature}")
+    }
+
+    /// 署名と有効期限を確かめる。見積もりの中身がリクエストと合っているかは呼び出し側で確かめる
+    pub fn verify(&self, quote_id: &str) -> Result<FareQuote, Error> {
+        let (payload, signature) = quote_id
+            .split_once('.')
+            .ok_or(Error::BadRequest("invalid quote"))?;
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| Error::BadRequest("invalid quote"))?;
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| Error::BadRequest("invalid quote"))?;
+
+        let quote: FareQuote = URL_SAFE_NO_PAD
+            .decode(payload)
+            .ok()
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .ok_or(Error::BadRequest("invalid quote"))?;
+        if quote.expires_at < chrono::Utc::now().timestamp_millis() {
+            return Err(Error::BadRequest("quote has expired"));
+        }
+        Ok(quote)
+    }
+
+    fn mac(&self, payload: &str) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> QuoteSigner {
+        QuoteSigner {
+            key: b"test-secret".to_vec(),
+        }
+    }
+
+    fn quote() -> FareQuote {
+        FareQuote::new(
+            "user".to_owned(),
+            Coordinate {
+                latitude: 0,
+                longitude: 0,
+            },
+            Coordinate {
+                latitude: 3,
+                longitude: 4,
+            },
+            1200,
+            1.2,
+            Some("CP_NEW2024".to_owned()),
+        )
+    }
+
+    fn is_invalid(result: Result<FareQuote, Error>) -> bool {
+        matches!(result, Err(Error::BadRequest("invalid quote")))
+    }
+
+    #[test]
+    fn verify_returns_the_signed_quote() {
+        let quote = quote();
+        let verified = signer().verify(&signer().sign(&quote)).unwrap();
+        assert_eq!(verified.id, quote.id);
+        assert_eq!(verified.user_id, quote.user_id);
+        assert_eq!(verified.pickup, quote.pickup);
+        assert_eq!(verified.destination, quote.destination);
+        assert_eq!(verified.fare, quote.fare);
+        assert_eq!(verified.surge_multiplier, quote.surge_multiplier);
+        assert_eq!(verified.coupon_code, quote.coupon_code);
+        assert_eq!(verified.expires_at, quote.expires_at);
+    }
+
+    #[test]
+    fn each_quote_gets_its_own_id() {
+        assert_ne!(quote().id, quote().id);
+    }
+
+    #[test]
+    fn verify_rejects_a_tampered_payload() {
+        let signed = signer().sign(&quote());
+        let (_, signature) = signed.split_once('.').unwrap();
+        let cheaper = FareQuote { fare: 1, ..quote() };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&cheaper).unwrap());
+        assert!(is_invalid(
+            signer().verify(&format!("{payload}.{signature}"))
+        ));
+    }
+
+    #[test]
+    fn verify_rejects_a_tampered_or_foreign_signature() {
+        let signed = signer().sign(&quote());
+        let (payload, _) = signed.split_once('.').unwrap();
+        let forged = URL_SAFE_NO_PAD.encode([0u8; 32]);
+        assert!(is_invalid(signer().verify(&format!("{payload}.{forged}"))));
+        assert!(is_invalid(signer().verify(&format!("{payload}.!"))));
+        assert!(is_invalid(signer().verify(payload)));
+
+        let other = QuoteSigner {
+            key: b"other-secret".to_vec(),
+        };
+        assert!(is_invalid(other.verify(&signed)));
+    }
+
+    #[test]
+    fn verify_rejects_an_expired_quote() {
+        let expired = FareQuote {
+            expires_at: chrono::Utc::now().timestamp_millis() - 1,
+            ..quote()
+        };
+        assert!(matches!(
+            signer().verify(&signer().sign(&expired)),
+            Err(Error::BadRequest("quote has expired"))
+        ));
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::models::{Payment, PaymentStatus, PaymentToken, Refund, RefundRequester, RefundStatus};
+use crate::payment_gateway::PaymentGatewayPostRefundRequest;
+use crate::{AppState, Error};
+
+/// 返金の依頼中にプロセスが落ちるなどして PENDING のまま残った返金を探す間隔
+const REFUND_RECOVERY_INTERVAL: Duration = Duration::from_secs(30);
+/// 決済サービスのクライアントがリトライし尽くすのに十分な時間。依頼中の返金はこれだけ待ってから送り直す
+const STALE_REFUND_AGE: Duration = Duration::from_secs(120);
+/// 結果が分からない返金を送る回数の上限。使い切ったら UNRESOLVED にして人が確かめる
+const MAX_REFUND_ATTEMPTS: i32 = 5;
+const MAX_REFUND_RETRY_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PostRefundRequest {
+    /// 省略したときは返金されていない残りの全額を返金する
+    pub amount: Option<i32>,
+    pub reason: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct RefundResponse {
+    pub id: String,
+    pub ride_id: String,
+    pub amount: i32,
+    pub reason: String,
+    pub requested_by: RefundRequester,
+    pub status: RefundStatus,
+    /// 返金後にこのライドで実際に請求されている額
+    pub charged_amount: i32,
+    pub created_at: i64,
+}
+
+/// 返金が成立した額の合計
+pub async fn refunded_amount(tx: &mut sqlx::MySqlConnection, ride_id: &str) -> sqlx::Result<i32> {
+    let amount: i64 = sqlx::query_scalar(
+        "SELECT CAST(IFNULL(SUM(amount), 0) AS SIGNED) FROM refunds WHERE ride_id = ? AND status = 'SUCCEEDED'",
+    )
+    .bind(ride_id)
+    .fetch_one(tx)
+    .await?;
+    Ok(amount as i32)
+}
+
+/// 椅子が担当したライドごとの、返金が成立した額の合計
+pub async fn refunded_amounts_by_chair(
+    tx: &mut sqlx::MySqlConnection,
+    chair_id: &str,
+) -> sqlx::Result<HashMap<String, i32>> {
+    let rows: Vec<(String, i64)> = sqlx::query_as(
+        "SELECT refunds.ride_id, CAST(SUM(refunds.amount) AS SIGNED) FROM refunds JOIN rides ON rides.id = refunds.ride_id WHERE rides.chair_id = ? AND refunds.status = 'SUCCEEDED' GROUP BY refunds.ride_id",
+    )
+    .bind(chair_id)
+    .fetch_all(tx)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(ride_id, amount)| (ride_id, amount as i32))
+        .collect())
+}
+
+/// 決済済みのライドの全額または一部を返金する。
+/// 返金を記録してから決済サービスに返金を依頼し、その結果で返金の状態を更新する。
+/// 結果が分からないまま終わった返金は `run_refund_recovery` が同じ Idempotency-Key で送り直す。
+pub async fn issue_refund(
+    state: &AppState,
+    ride_id: &str,
+    req: PostRefundRequest,
+    requested_by: RefundRequester,
+) -> Result<RefundResponse, Error> {
+    if req.reason.trim().is_empty() {
+        return Err(Error::BadRequest("reason is required"));
+    }
+
+    let mut tx = state.pool.begin().await?;
+
+    let Some(payment): Option<Payment> =
+        sqlx::query_as("SELECT * FROM payments WHERE ride_id = ? FOR UPDATE")
+            .bind(ride_id)
+            .fetch_optional(&mut *tx)
+            .await?
+    else {
+        return Err(Error::NotFound("payment not found"));
+    };
+    if payment.status != PaymentStatus::Succeeded {
+        return Err(Error::BadRequest("payment has not been settled"));
+    }
+
+    // 処理中の返金も差し引いて、請求額を超えて返金しないようにする
+    let reserved: i64 = sqlx::query_scalar(
+        "SELECT CAST(IFNULL(SUM(amount), 0) AS SIGNED) FROM refunds WHERE ride_id = ? AND status != 'FAILED'",
+    )
+    .bind(ride_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    let remaining = payment.amount - reserved as i32;
+    let amount = req.amount.unwrap_or(remaining);
+    if amount <= 0 {
+        return Err(Error::BadRequest("nothing to refund"));
+    }
+    if amount > remaining {
+        return Err(Error::BadRequest(
+            "refund amount exceeds the remaining charge",
+        ));
+    }
+
+    // 請求に使った支払い方法に返金する
+    let payment_token_exists: bool =
+        sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM payment_tokens WHERE id = ?)")
+            .bind(&payment.payment_token_id)
+            .fetch_one(&mut *tx)
+            .await?;
+    if !payment_token_exists {
+        return Err(Error::BadRequest("payment method has been removed"));
+    }
+
+    let refund_id = ulid::Ulid::new().to_string();
+    sqlx::query("INSERT INTO refunds (id, ride_id, payment_id, amount, reason, requested_by, idempotency_key, next_attempt_at) VALUES (?, ?, ?, ?, ?, ?, ?, DATE_ADD(NOW(6), INTERVAL ? SECOND))")
+        .bind(&refund_id)
+        .bind(ride_id)
+        .bind(&payment.id)
+        .bind(amount)
+        .bind(&req.reason)
+        .bind(requested_by)
+        .bind(ulid::Ulid::new().to_string())
+        .bind(STALE_REFUND_AGE.as_secs())
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    let refund: Refund = sqlx::query_as("SELECT * FROM refunds WHERE id = ?")
+        .bind(&refund_id)
+        .fetch_one(&state.pool)
+        .await?;
+    send_refund(state, &refund).await?;
+
+    let mut conn = state.pool.acquire().await?;
+    let refunded = refunded_amount(&mut conn, ride_id).await?;
+    Ok(RefundResponse {
+        id: refund.id,
+        ride_id: refund.ride_id,
+        amount: refund.amount,
+        reason: refund.reason,
+        requested_by: refund.requested_by,
+        status: RefundStatus::Succeeded,
+        charged_amount: payment.amount - refunded,
+        created_at: refund.created_at.timestamp_millis(),
+    })
+}
+
+fn refund_retry_backoff(attempts: i32) -> Duration {
+    let exponent = u32::try_from(attempts.clamp(0, 16)).unwrap();
+    (REFUND_RECOVERY_INTERVAL * 2u32.pow(exponent)).min(MAX_REFUND_RETRY_BACKOFF)
+}
+
+/// 記録した返金を決済サービスに依頼し、その結果で返金の状態を更新する。
+/// 決済サービスが断ったときだけ FAILED にする。5xx や通信エラーでは返金が通っているかもしれないので
+/// PENDING のまま残し、間を空けて同じ Idempotency-Key で送り直す (決済サービスが二重の返金を弾く)。
+/// `MAX_REFUND_ATTEMPTS` 回送っても分からなければ UNRESOLVED にして諦める
+async fn send_refund(state: &AppState, refund: &Refund) -> Result<(), Error> {
+    let payment: Payment = sqlx::query_as("SELECT * FROM payments WHERE id = ?")
+        .bind(&refund.payment_id)
+        .fetch_one(&state.pool)
+        .await?;
+    let payment_token: Option<PaymentToken> =
+        sqlx::query_as("SELECT * FROM payment_tokens WHERE id = ?")
+            .bind(&payment.payment_token_id)
+            .fetch_optional(&state.pool)
+            .await?;
+    let result = match payment_token {
+        Some(payment_token) => {
+            let payment_gateway_url: String =
+                sqlx::query_scalar("SELECT value FROM settings WHERE name = 'payment_gateway_url'")
+                    .fetch_one(&state.pool)
+                    .await?;
+            state
+                .payment_gateway
+                .post_refund(
+                    &payment_gateway_url,
+                    &payment_token.token,
+                    &refund.idempotency_key,
+                    &PaymentGatewayPostRefundRequest {
+                        payment_idempotency_key: payment.charged_idempotency_key().to_owned(),
+                        amount: refund.amount,
+                    },
+                )
+                .await
+        }
+        None => Err(Error::BadRequest("payment method has been removed")),
+    };
+
+    match result {
+        Ok(()) => {
+            sqlx::query("UPDATE refunds SET status = 'SUCCEEDED', attempts = attempts + 1, last_error = NULL WHERE id = ?")
+                .bind(&refund.id)
+                .execute(&state.pool)
+                .await?;
+            info!(
+                refund_id = refund.id,
+                ride_id = refund.ride_id,
+                amount = refund.amount,
+                "refund succeeded"
+            );
+            Ok(())
+        }
+        Err(e) if matches!(e, Error::BadRequest(_)) || crate::payments::is_declined(&e) => {
+            sqlx::query("UPDATE refunds SET status = 'FAILED', attempts = attempts + 1, last_error = ? WHERE id = ?")
+                .bind(e.to_string())
+                .bind(&refund.id)
+                .execute(&state.pool)
+                .await?;
+            warn!(
+                refund_id = refund.id,
+                ride_id = refund.ride_id,
+                e = e.to_string(),
+                "refund failed"
+            );
+            Err(e)
+        }
+        Err(e) if refund.attempts + 1 < MAX_REFUND_ATTEMPTS => {
+            let backoff = refund_retry_backoff(refund.attempts);
+            sqlx::query("UPDATE refunds SET attempts = attempts + 1, last_error = ?, next_attempt_at = DATE_ADD(NOW(6), INTERVAL ? SECOND) WHERE id = ?")
+                .bind(e.to_string())
+                .bind(backoff.as_secs())
+                .bind(&refund.id)
+                .execute(&state.pool)
+                .await?;
+            warn!(
+                refund_id = refund.id,
+                ride_id = refund.ride_id,
+                attempts = refund.attempts + 1,
+                backoff_secs = backoff.as_secs(),
+                e = e.to_string(),
+                "refund result unknown, will retry"
+            );
+            Err(e)
+        }
+        Err(e) => {
+            sqlx::query("UPDATE refunds SET status = 'UNRESOLVED', attempts = attempts + 1, last_error = ? WHERE id = ?")
+                .bind(e.to_string())
+                .bind(&refund.id)
+                .execute(&state.pool)
+                .await?;
+            warn!(
+                refund_id = refund.id,
+                ride_id = refund.ride_id,
+                attempts = refund.attempts + 1,
+                e = e.to_string(),
+                "refund result still unknown, giving up"
+            );
+            Err(e)
+        }
+    }
+}
+
+/// PENDING のまま残った返金を、送り直す時刻が来たものから送り直す
+pub async fn run_refund_recovery(state: AppState) {
+    loop {
+        tokio::time::sleep(REFUND_RECOVERY_INTERVAL).await;
+        let refunds: Vec<Refund> = match sqlx::query_as(
+            "SELECT * FROM refunds WHERE status = 'PENDING' AND next_attempt_at <= NOW(6) ORDER BY next_attempt_at LIMIT 100",
+        )
+        .fetch_all(&state.pool)
+        .await
+        {
+            Ok(refunds) => refunds,
+            Err(e) => {
+                warn!(e = e.to_string(), "failed to find stale refunds");
+                continue;
+            }
+        };
+        for refund in refunds {
+            info!(refund_id = refund.id, "resending stale refund");
+            // 失敗は send_refund が記録している
+            let _ = send_refund(&state, &refund).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refund_retry_backoff_grows_and_is_capped() {
+        assert_eq!(refund_retry_backoff(0), REFUND_RECOVERY_INTERVAL);
+        assert_eq!(refund_retry_backoff(1), REFUND_RECOVERY_INTERVAL * 2);
+        assert!(refund_retry_backoff(3) > refund_retry_backoff(2));
+        assert_eq!(
+            refund_retry_backoff(MAX_REFUND_ATTEMPTS * 10),
+            MAX_REFUND_RETRY_BACKOFF
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::Coordinate;
+
+/// 一辺 `cell_size` の正方形のセルに座標を振り分ける一様グリッド。
+/// マンハッタン距離で一定範囲内にある点を、範囲に掛かるセルだけ見て探す。
+#[derive(Debug)]
+pub struct GridIndex {
+    cell_size: i32,
+    cells: HashMap<(i32, i32), HashSet<String>>,
+    positions: HashMap<String, Coordinate>,
+}
+impl GridIndex {
+    pub fn new(cell_size: i32) -> Self {
+        assert!(cell_size > 0, "cell_size must be positive");
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+            positions: HashMap::new(),
+        }
+    }
+
+    fn cell_of(&self, coordinate: Coordinate) -> (i32, i32) {
+        (
+            coordinate.latitude.div_euclid(self.cell_size),
+            coordinate.longitude.div_euclid(self.cell_size),
+        )
+    }
+
+    pub fn upsert(&mut self, id: &str, coordinate: Coordinate) {
+        let cell = self.cell_of(coordinate);
+        if let Some(previous) = self.positions.insert(id.to_owned(), coordinate) {
+            let previous_cell = self.cell_of(previous);
+            if previous_cell == cell {
+                return;
+            }
+            if let Some(ids) = self.cells.get_mut(&previous_cell) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.cells.remove(&previous_cell);
+                }
+            }
+        }
+        self.cells.entry(cell).or_default().insert(id.to_owned());
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        let Some(previous) = self.positions.remove(id) else {
+            return;
+        };
+        let previous_cell = self.cell_of(previous);
+        if let Some(ids) = self.cells.get_mut(&previous_cell) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.cells.remove(&previous_cell);
+            }
+        }
+    }
+
+    /// `center` からマンハッタン距離 `distance` 以内にある点を、近い順に最大 `limit` 件返す
+    pub fn within(
+        &self,
+        center: Coordinate,
+        distance: i32,
+        limit: Option<usize>,
+    ) -> Vec<(String, Coordinate, i32)> {
+        if distance < 0 {
+            return Vec::new();
+        }
+        let (min_lat, min_lon) = self.cell_of(Coordinate {
+            latitude: center.latitude.saturating_sub(distance),
+            longitude: center.longitude.saturating_sub(distance),
+        });
+        let (max_lat, max_lon) = self.cell_of(Coordinate {
+            latitude: center.latitude.saturating_add(distance),
+            longitude: center.longitude.saturating_add(distance),
+        });
+
+        let mut found = Vec::new();
+        let mut visit = |ids: &HashSet<String>| {
+            for id in ids {
+                let coordinate = self.positions[id];
+                let d = crate::calculate_distance(
+                    center.latitude,
+                    center.longitude,
+                    coordinate.latitude,
+                    coordinate.longitude,
+                );
+                if d <= distance {
+                    found.push((id.clone(), coordinate, d));
+                }
+            }
+        };
+
+        // 範囲が広すぎるときは空のセルを数え上げるより、点のあるセルを全部見た方が早い
+        let range_cells = (i64::from(max_lat) - i64::from(min_lat) + 1)
+            * (i64::from(max_lon) - i64::from(min_lon) + 1);
+        if range_cells > self.cells.len() as i64 {
+            for (&(cell_lat, cell_lon), ids) in &self.cells {
+                if (min_lat..=max_lat).contains(&cell_lat)
+                    && (min_lon..=max_lon).contains(&cell_lon)
+                {
+                    visit(ids);
+                }
+            }
+        } else {
+            for cell_lat in min_lat..=max_lat {
+                for cell_lon in min_lon..=max_lon {
+                    if let Some(ids) = self.cells.get(&(cell_lat, cell_lon)) {
+                        visit(ids);
+                    }
+                }
+            }
+        }
+
+        found.sort_by(|a, b| a.2.cmp(&b.2).then_with(|| a.0.cmp(&b.0)));
+        if let Some(limit) = limit {
+            found.truncate(limit);
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(latitude: i32, longitude: i32) -> Coordinate {
+        Coordinate {
+            latitude,
+            longitude,
+        }
+    }
+
+    fn ids(found: Vec<(String, Coordinate, i32)>) -> Vec<String> {
+        found.into_iter().map(|(id, _, _)| id).collect()
+    }
+
+    #[test]
+    fn within_includes_points_across_cell_boundaries() {
+        let mut grid = GridIndex::new(10);
+        grid.upsert("left", at(9, 0));
+        grid.upsert("right", at(10, 0));
+        grid.upsert("negative", at(-1, 0));
+        assert_eq!(ids(grid.within(at(10, 0), 1, None)), vec!["right", "left"]);
+        assert_eq!(ids(grid.within(at(0, 0), 1, None)), vec!["negative"]);
+    }
+
+    #[test]
+    fn within_uses_manhattan_distance_and_sorts_by_distance() {
+        let mut grid = GridIndex::new(4);
+        grid.upsert("a", at(3, 3));
+        grid.upsert("b", at(5, 0));
+        grid.upsert("c", at(4, 2));
+        grid.upsert("d", at(-2, -2));
+        assert_eq!(
+            grid.within(at(0, 0), 6, None),
+            vec![
+                ("d".to_owned(), at(-2, -2), 4),
+                ("b".to_owned(), at(5, 0), 5),
+                ("a".to_owned(), at(3, 3), 6),
+                ("c".to_owned(), at(4, 2), 6),
+            ]
+        );
+        assert_eq!(ids(grid.within(at(0, 0), 5, Some(1))), vec!["d"]);
+        assert!(grid.within(at(0, 0), -1, None).is_empty());
+    }
+
+    #[test]
+    fn within_with_a_radius_covering_many_cells() {
+        let mut grid = GridIndex::new(1);
+        grid.upsert("near", at(100, 100));
+        grid.upsert("far", at(10_000, 10_000));
+        assert_eq!(ids(grid.within(at(0, 0), 1_000, None)), vec!["near"]);
+    }
+
+    #[test]
+    fn upsert_moves_points_and_remove_forgets_them() {
+        let mut grid = GridIndex::new(10);
+        grid.upsert("chair", at(0, 0));
+        grid.upsert("chair", at(25, 0));
+        assert!(grid.within(at(0, 0), 5, None).is_empty());
+        assert_eq!(ids(grid.within(at(25, 0), 0, None)), vec!["chair"]);
+
+        grid.remove("chair");
+        assert!(grid.within(at(25, 0), 100, None).is_empty());
+        assert!(grid.cells.is_empty());
+    }
+}
@@ -6,3 +6,21 @@ ISUCON_DB_NAME="isuride"
 
 # マッチング間隔（秒）
 ISUCON_MATCHING_INTERVAL=0.5
+
+# マッチング戦略 (fifo, nearest, eta, optimal)
+ISUCON_MATCHING_STRATEGY=optimal
+
+# 椅子がライドを受理するまでの待ち時間（秒）。過ぎると割り当てを外して再マッチングする
+ISUCON_MATCHING_ACCEPT_TIMEOUT=3
+
+# 複数のインスタンスで動かすときに通知を中継するブローカーのアドレス (例: 192.168.0.13:5555)。空なら中継しない
+ISUCON_NOTIFICATION_BROKER=
+
+# 運賃の見積もりの ID に署名する鍵。複数のインスタンスで動かすときは揃える。空なら起動ごとに作る
+ISUCON_QUOTE_SECRET=
+
+# 決済の定期的な突き合わせで、決済サービスに記録が無い請求を同じ Idempotency-Key で送り直すか (1 で有効)。既定では報告だけ
+ISUCON_PAYMENT_RECONCILE_REPOST=0
+
+# マッチングと決済のワーカーをこのインスタンスで動かすか (0 で止める)。複数のインスタンスで動かすときは 1 台だけ 1 にする
+ISUCON_BACKGROUND_WORKERS={{ 1 if isuride_background_workers | default(true) else 0 }}